@@ -3,30 +3,181 @@
 //     level as their item
 #![allow(non_local_definitions)]
 
+use crate::retrieve::retriever::RetrieveError;
+use crate::retrieve::signature::SignatureError;
+use crate::send::history::HistoryError;
+use crate::send::resize::ImageError;
+use crate::send::sender::SaveError;
+use crate::send::versions::VersionError;
+use crate::storage::encrypted::EncryptionError;
+use crate::storage::filesystem::FilesystemError;
+use crate::storage::is_not_found;
+use crate::storage::mirror::MirrorError;
+use crate::storage::name::NameError;
+use crate::storage::retry::retryable;
+use crate::storage::s3;
+use crate::storage::StorageError;
 use actix_web::error::ResponseError;
+use actix_web::http::StatusCode;
 use actix_web::HttpResponse;
 use dino_park_trust::GroupsTrustError;
 use dino_park_trust::TrustError;
 use failure::Fail;
 use log::warn;
 use serde_json::json;
-use serde_json::Value;
-use std::fmt::Display;
+
+/// Stable error codes, sent along a message as `{"code": …, "message": …}`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Code {
+    Multipart,
+    UnknownSize,
+    Forbidden,
+    InvalidSignature,
+    NotFound,
+    MissingIntermediate,
+    UnknownVersion,
+    NothingToRestore,
+    UuidMismatch,
+    Expired,
+    TooLarge,
+    UnsupportedFormat,
+    InvalidImage,
+    InvalidName,
+    Internal,
+    VersionsUnsupported,
+    Storage,
+    Unavailable,
+}
+
+impl Code {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Code::Multipart => "multipart",
+            Code::UnknownSize => "unknown_size",
+            Code::Forbidden => "forbidden",
+            Code::InvalidSignature => "invalid_signature",
+            Code::NotFound => "not_found",
+            Code::MissingIntermediate => "missing_intermediate",
+            Code::UnknownVersion => "unknown_version",
+            Code::NothingToRestore => "nothing_to_restore",
+            Code::UuidMismatch => "uuid_mismatch",
+            Code::Expired => "expired",
+            Code::TooLarge => "too_large",
+            Code::UnsupportedFormat => "unsupported_format",
+            Code::InvalidImage => "invalid_image",
+            Code::InvalidName => "invalid_name",
+            Code::Internal => "internal",
+            Code::VersionsUnsupported => "versions_unsupported",
+            Code::Storage => "storage",
+            Code::Unavailable => "unavailable",
+        }
+    }
+
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Code::Multipart | Code::UnknownSize => StatusCode::BAD_REQUEST,
+            Code::Forbidden | Code::InvalidSignature => StatusCode::FORBIDDEN,
+            Code::NotFound
+            | Code::MissingIntermediate
+            | Code::UnknownVersion
+            | Code::NothingToRestore => StatusCode::NOT_FOUND,
+            Code::UuidMismatch => StatusCode::CONFLICT,
+            Code::Expired => StatusCode::GONE,
+            Code::TooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            Code::UnsupportedFormat => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Code::InvalidImage | Code::InvalidName => StatusCode::UNPROCESSABLE_ENTITY,
+            Code::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+            Code::VersionsUnsupported => StatusCode::NOT_IMPLEMENTED,
+            Code::Storage => StatusCode::BAD_GATEWAY,
+            Code::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// Classifies an error of the send, retrieve or storage layers.
+    pub fn of(e: &failure::Error) -> Self {
+        if let Some(e) = e.downcast_ref::<SaveError>() {
+            return match e {
+                SaveError::UuidMismatch => Code::UuidMismatch,
+                SaveError::NothingToRestore => Code::NothingToRestore,
+                SaveError::MissingIntermediate => Code::MissingIntermediate,
+                SaveError::NotArchived => Code::Storage,
+            };
+        }
+        if let Some(e) = e.downcast_ref::<RetrieveError>() {
+            return match e {
+                RetrieveError::NotFound => Code::NotFound,
+                RetrieveError::UnknownSize => Code::UnknownSize,
+            };
+        }
+        if let Some(e) = e.downcast_ref::<SignatureError>() {
+            return match e {
+                SignatureError::Invalid => Code::InvalidSignature,
+                SignatureError::Expired => Code::Expired,
+            };
+        }
+        if let Some(e) = e.downcast_ref::<ImageError>() {
+            return match e {
+                ImageError::UnsupportedFormat => Code::UnsupportedFormat,
+                ImageError::AspectRatio(_) => Code::InvalidImage,
+            };
+        }
+        if let Some(HistoryError::UnknownVersion) = e.downcast_ref::<HistoryError>() {
+            return Code::UnknownVersion;
+        }
+        if let Some(VersionError::UnknownSize) = e.downcast_ref::<VersionError>() {
+            return Code::UnknownSize;
+        }
+        if let Some(StorageError::VersionsUnsupported) = e.downcast_ref::<StorageError>() {
+            return Code::VersionsUnsupported;
+        }
+        if e.downcast_ref::<image::ImageError>().is_some() {
+            return Code::InvalidImage;
+        }
+        if e.downcast_ref::<NameError>().is_some() {
+            return Code::InvalidName;
+        }
+        if is_not_found(e) {
+            return Code::NotFound;
+        }
+        // transient failures which outlasted the retries
+        if retryable(e) {
+            return Code::Unavailable;
+        }
+        if e.downcast_ref::<std::io::Error>().is_some()
+            || e.downcast_ref::<FilesystemError>().is_some()
+            || e.downcast_ref::<MirrorError>().is_some()
+            || e.downcast_ref::<EncryptionError>().is_some()
+            || s3::is_s3_error(e)
+        {
+            return Code::Storage;
+        }
+        Code::Internal
+    }
+}
 
 #[derive(Fail, Debug)]
 pub enum ApiError {
     #[fail(display = "Multipart error occurred.")]
     MultipartError,
-    #[fail(display = "Bad API request.")]
-    GenericBadRequest(failure::Error),
+    #[fail(display = "Upload exceeds {} bytes.", _0)]
+    PayloadTooLarge(usize),
     #[fail(display = "Scope Error: {}", _0)]
     ScopeError(TrustError),
     #[fail(display = "Groups scope Error: {}", _0)]
     GroupsScopeError(GroupsTrustError),
+    #[fail(display = "{}", _1)]
+    Failed(Code, failure::Error),
 }
 
-fn to_json_error(e: &impl Display) -> Value {
-    json!({ "error": e.to_string() })
+impl ApiError {
+    pub fn code(&self) -> Code {
+        match *self {
+            Self::MultipartError => Code::Multipart,
+            Self::PayloadTooLarge(_) => Code::TooLarge,
+            Self::ScopeError(_) | Self::GroupsScopeError(_) => Code::Forbidden,
+            Self::Failed(code, _) => code,
+        }
+    }
 }
 
 impl From<TrustError> for ApiError {
@@ -43,20 +194,78 @@ impl From<GroupsTrustError> for ApiError {
 
 impl From<failure::Error> for ApiError {
     fn from(e: failure::Error) -> Self {
-        ApiError::GenericBadRequest(e)
+        ApiError::Failed(Code::of(&e), e)
     }
 }
 
 impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.code().status()
+    }
+
     fn error_response(&self) -> HttpResponse {
-        match *self {
-            Self::GenericBadRequest(ref e) => {
-                warn!("{}", e);
-                HttpResponse::BadRequest().finish()
-            }
-            Self::ScopeError(ref e) => HttpResponse::Forbidden().json(to_json_error(e)),
-            Self::GroupsScopeError(ref e) => HttpResponse::Forbidden().json(to_json_error(e)),
-            _ => HttpResponse::InternalServerError().finish(),
-        }
+        let status = self.status_code();
+        // details of server side failures only go to the log
+        let message = if status.is_server_error() {
+            warn!("{}", self);
+            status.canonical_reason().unwrap_or_default().to_owned()
+        } else {
+            self.to_string()
+        };
+        HttpResponse::build(status).json(json!({
+            "code": self.code().as_str(),
+            "message": message,
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::memory::MemoryError;
+    use crate::storage::retry::RetryError;
+    use actix_web::body::to_bytes;
+    use serde_json::Value;
+
+    fn code(e: impl Fail) -> Code {
+        ApiError::from(failure::Error::from(e)).code()
+    }
+
+    #[test]
+    fn test_codes() {
+        assert_eq!(code(SaveError::UuidMismatch), Code::UuidMismatch);
+        assert_eq!(
+            code(SaveError::MissingIntermediate),
+            Code::MissingIntermediate
+        );
+        assert_eq!(code(ImageError::UnsupportedFormat), Code::UnsupportedFormat);
+        assert_eq!(code(ImageError::AspectRatio(2.0)), Code::InvalidImage);
+        assert_eq!(code(NameError::InvalidName), Code::InvalidName);
+        assert_eq!(code(MemoryError::NotFound), Code::NotFound);
+        assert_eq!(code(RetryError::Timeout(String::new())), Code::Unavailable);
+        assert_eq!(code(EncryptionError::Decrypt), Code::Storage);
+        assert_eq!(code(SignatureError::Expired), Code::Expired);
+        assert_eq!(
+            ApiError::from(failure::format_err!("oops")).code(),
+            Code::Internal
+        );
+        assert_eq!(ApiError::PayloadTooLarge(1).status_code().as_u16(), 413);
+    }
+
+    #[actix_rt::test]
+    async fn test_json_body() {
+        let res = ApiError::from(failure::Error::from(SaveError::UuidMismatch)).error_response();
+        assert_eq!(res.status(), StatusCode::CONFLICT);
+        let body: Value =
+            serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap();
+        assert_eq!(
+            body,
+            json!({"code": "uuid_mismatch", "message": "uuid mismatch"})
+        );
+
+        let res = ApiError::from(failure::Error::from(EncryptionError::Decrypt)).error_response();
+        let body: Value =
+            serde_json::from_slice(&to_bytes(res.into_body()).await.unwrap()).unwrap();
+        assert_eq!(body, json!({"code": "storage", "message": "Bad Gateway"}));
     }
 }
@@ -19,27 +19,38 @@ use cis_client::CisClient;
 use dino_park_gate::provider::Provider;
 use dino_park_gate::scope::ScopeAndUserAuth;
 use log::info;
+use log::warn;
 use lru_time_cache::LruCache;
 use retrieve::app::retrieve_app;
 use send::app::internal_send_app;
 use send::app::send_app;
+use send::operations::derivative_prefixes;
+use send::rehash::rehash_uuid;
+use settings::AvatarSettings;
+use settings::DedupSettings;
+use settings::StorageSettings;
 use std::io::Error;
+use std::sync::Arc;
 use std::sync::Mutex;
+use storage::backend::Backend;
+use storage::cache::CachingStorage;
+use storage::dedup::DedupStorage;
+use storage::encrypted::EncryptedStorage;
+use storage::encrypted::Keyring;
+use storage::retry::RetryingStorage;
+use storage::sweep_tmp;
+use storage::Storage;
 
 fn map_io_err(e: impl Into<failure::Error>) -> Error {
     Error::other(e.into())
 }
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    ::std::env::set_var("RUST_LOG", "actix_web=info,dino_park_fossil=info");
-    env_logger::init();
-    info!("building the fossil");
-    let s = settings::Settings::new().map_err(map_io_err)?;
-    let cis_client = Data::new(CisClient::from_settings(&s.cis).await.map_err(map_io_err)?);
-    let avatar_settings = Data::new(s.avatar.clone());
-    let provider = Provider::from_issuer(&s.auth).await.map_err(map_io_err)?;
-
+async fn serve<S: Storage + 'static>(
+    storage: Data<S>,
+    cis_client: Data<CisClient>,
+    avatar_settings: Data<AvatarSettings>,
+    provider: Provider,
+) -> std::io::Result<()> {
     let time_to_live = ::std::time::Duration::from_secs(60 * 60 * 24);
     let cache = Data::new(Mutex::new(
         LruCache::<String, String>::with_expiry_duration_and_capacity(time_to_live, 2000),
@@ -48,70 +59,193 @@ async fn main() -> std::io::Result<()> {
     HttpServer::new(move || {
         let scope_middleware = ScopeAndUserAuth::new(provider.clone()).public();
 
-        #[cfg(feature = "local-fs")]
-        {
-            use crate::storage::loader::filesystem::FilesystemLoader;
-            use crate::storage::saver::filesystem::FilesystemSaver;
-            use std::path::PathBuf;
-            use std::sync::Arc;
+        App::new()
+            .wrap(Logger::default().exclude("/healthz"))
+            .app_data(storage.clone())
+            .app_data(cache.clone())
+            .app_data(cis_client.clone())
+            .app_data(avatar_settings.clone())
+            .service(
+                web::scope("/avatar")
+                    .wrap(scope_middleware)
+                    .service(retrieve_app::<CisClient, S>())
+                    .service(send_app::<S, S>()),
+            )
+            .service(internal_send_app::<S>())
+            .service(healthz::healthz_app())
+    })
+    .bind("0.0.0.0:8083")?
+    .run()
+    .await
+}
 
-            let mut path = PathBuf::new();
-            path.push("./files");
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    ::std::env::set_var("RUST_LOG", "actix_web=info,dino_park_fossil=info");
+    env_logger::init();
+    info!("building the fossil");
+    let s = settings::Settings::new().map_err(map_io_err)?;
+    let mut storage_settings = s.storage.clone();
+    let args = std::env::args().collect::<Vec<_>>();
+    if args.iter().any(|arg| arg == "--storage=memory") {
+        let mut fail_nth_save = None;
+        let mut fail_prefix = None;
+        for arg in &args {
+            if let Some(n) = arg.strip_prefix("--fail-nth-save=") {
+                fail_nth_save = Some(n.parse().map_err(map_io_err)?);
+            } else if let Some(prefix) = arg.strip_prefix("--fail-prefix=") {
+                fail_prefix = Some(prefix.to_owned());
+            }
+        }
+        storage_settings = StorageSettings::Memory {
+            fail_nth_save,
+            fail_prefix,
+        };
+    }
+    info!("using {:?} storage", storage_settings);
+    let backend = Backend::from_settings(&storage_settings, &s.avatar).map_err(map_io_err)?;
+    if args.iter().any(|arg| arg == "--migrate-fs-layout") {
+        let moved = backend
+            .migrate_layout(&s.avatar.s3_bucket)
+            .await
+            .map_err(map_io_err)?;
+        info!("migrated {} files to the sharded layout", moved);
+        return Ok(());
+    }
 
-            let saver = Data::new(FilesystemSaver {
-                path: Arc::new(path.clone()),
-            });
-            let loader = Data::new(FilesystemLoader {
-                path: Arc::new(path),
-            });
+    let avatar_settings = Data::new(s.avatar.clone());
+    // Rehashing goes through the same layers as serving, so sealed objects
+    // are sealed again for their new owner.
+    let task = match args
+        .iter()
+        .find_map(|arg| arg.strip_prefix("--rehash-uuids="))
+    {
+        Some(path) => Task::Rehash(
+            std::fs::read_to_string(path)?
+                .lines()
+                .map(str::trim)
+                .filter(|uuid| !uuid.is_empty())
+                .map(str::to_owned)
+                .collect(),
+        ),
+        None => Task::Serve {
+            sweep: !backend.expires_tmp(),
+            cis_client: Data::new(CisClient::from_settings(&s.cis).await.map_err(map_io_err)?),
+            provider: Provider::from_issuer(&s.auth).await.map_err(map_io_err)?,
+        },
+    };
+
+    if let (Backend::Mirror(mirror), Task::Serve { .. }) = (&backend, &task) {
+        let mirror = Arc::clone(mirror);
+        actix_web::rt::spawn(async move {
+            let mut interval = actix_web::rt::time::interval(::std::time::Duration::from_secs(60));
+            loop {
+                interval.tick().await;
+                mirror.repair().await;
+            }
+        });
+    }
+    let backend = RetryingStorage::new(backend, s.retry.clone());
+    let backend = CachingStorage::new(backend, &s.cache).map_err(map_io_err)?;
 
-            App::new()
-                .wrap(Logger::default().exclude("/healthz"))
-                .app_data(loader)
-                .app_data(cache.clone())
-                .app_data(saver)
-                .app_data(cis_client.clone())
-                .app_data(avatar_settings.clone())
-                .service(
-                    web::scope("/avatar")
-                        .wrap(scope_middleware)
-                        .service(retrieve_app::<CisClient, FilesystemLoader>())
-                        .service(send_app::<FilesystemSaver, FilesystemLoader>()),
-                )
-                .service(internal_send_app::<FilesystemSaver, FilesystemLoader>())
-                .service(healthz::healthz_app())
+    // Deduplication hashes plaintext, so it sits above the encryption.
+    match &s.encryption {
+        Some(encryption) => {
+            info!("encrypting non-public pictures");
+            let keyring = Keyring::from_settings(encryption).map_err(map_io_err)?;
+            dedup_and_run(
+                EncryptedStorage::new(backend, keyring),
+                &s.dedup,
+                task,
+                avatar_settings,
+            )
+            .await
         }
+        None => dedup_and_run(backend, &s.dedup, task, avatar_settings).await,
+    }
+}
 
-        #[cfg(not(feature = "local-fs"))]
-        {
-            use crate::storage::loader::s3::S3Loader;
-            use crate::storage::saver::s3::S3Saver;
-            use rusoto_s3::S3Client;
+/// What runs on top of the storage layers.
+enum Task {
+    Serve {
+        sweep: bool,
+        cis_client: Data<CisClient>,
+        provider: Provider,
+    },
+    /// Moves the pictures of these uuids to keyed hashes and exits.
+    Rehash(Vec<String>),
+}
 
-            let s3_client = S3Client::new(rusoto_core::Region::default());
-            let saver = Data::new(S3Saver {
-                s3_client: s3_client.clone(),
-            });
-            let loader = Data::new(S3Loader { s3_client });
+async fn dedup_and_run<S: Storage + 'static>(
+    backend: S,
+    dedup: &Option<DedupSettings>,
+    task: Task,
+    avatar_settings: Data<AvatarSettings>,
+) -> std::io::Result<()> {
+    match dedup {
+        Some(dedup) => {
+            info!("deduplicating derivatives");
+            let backend = DedupStorage::new(backend);
+            if let Task::Serve { .. } = task {
+                let storage = backend.clone();
+                let bucket = avatar_settings.s3_bucket.clone();
+                let grace = chrono::Duration::minutes(dedup.gc_grace_minutes);
+                let period = ::std::time::Duration::from_secs(60 * dedup.gc_interval_minutes);
+                actix_web::rt::spawn(async move {
+                    let prefixes = derivative_prefixes();
+                    let mut interval = actix_web::rt::time::interval(period);
+                    loop {
+                        interval.tick().await;
+                        if let Err(e) = storage.collect_garbage(&bucket, &prefixes, grace).await {
+                            warn!("collecting unreferenced blobs failed: {}", e);
+                        }
+                    }
+                });
+            }
+            run(Data::new(backend), task, avatar_settings).await
+        }
+        None => run(Data::new(backend), task, avatar_settings).await,
+    }
+}
 
-            App::new()
-                .wrap(Logger::default().exclude("/healthz"))
-                .app_data(loader)
-                .app_data(cache.clone())
-                .app_data(saver)
-                .app_data(cis_client.clone())
-                .app_data(avatar_settings.clone())
-                .service(
-                    web::scope("/avatar")
-                        .wrap(scope_middleware)
-                        .service(retrieve_app::<CisClient, S3Loader>())
-                        .service(send_app::<S3Saver, S3Loader>()),
-                )
-                .service(internal_send_app::<S3Saver, S3Loader>())
-                .service(healthz::healthz_app())
+async fn run<S: Storage + 'static>(
+    storage: Data<S>,
+    task: Task,
+    avatar_settings: Data<AvatarSettings>,
+) -> std::io::Result<()> {
+    let (sweep, cis_client, provider) = match task {
+        Task::Serve {
+            sweep,
+            cis_client,
+            provider,
+        } => (sweep, cis_client, provider),
+        Task::Rehash(uuids) => {
+            let storage = storage.into_inner();
+            let mut moved = 0;
+            for uuid in &uuids {
+                moved += rehash_uuid(uuid, &avatar_settings, &storage)
+                    .await
+                    .map_err(map_io_err)?;
+            }
+            info!("moved {} objects to keyed uuid hashes", moved);
+            return Ok(());
         }
-    })
-    .bind("0.0.0.0:8083")?
-    .run()
-    .await
+    };
+    if sweep {
+        let storage = storage.clone();
+        let bucket = avatar_settings.s3_bucket.clone();
+        let ttl = chrono::Duration::minutes(avatar_settings.tmp_ttl_minutes);
+        actix_web::rt::spawn(async move {
+            let mut interval =
+                actix_web::rt::time::interval(::std::time::Duration::from_secs(5 * 60));
+            loop {
+                interval.tick().await;
+                if let Err(e) = sweep_tmp(storage.get_ref(), &bucket, ttl).await {
+                    warn!("sweeping tmp files failed: {}", e);
+                }
+            }
+        });
+    }
+
+    serve(storage, cis_client, avatar_settings, provider).await
 }
@@ -1,20 +1,26 @@
+use crate::error::ApiError;
+use crate::error::Code;
+use crate::retrieve::retriever::check_signature;
 use crate::retrieve::retriever::retrieve_avatar_from_store;
+use crate::retrieve::signature::Signature;
 use crate::retrieve::uuid::get_uuid;
 use crate::settings::AvatarSettings;
 use crate::storage::loader::Loader;
+use crate::storage::name::ImageFormat;
 use actix_web::dev::HttpServiceFactory;
 use actix_web::error;
-use actix_web::http::header::{ContentEncoding, ContentType};
+use actix_web::http::header::{ContentEncoding, ETag, EntityTag, IfNoneMatch, CONTENT_TYPE};
 use actix_web::web;
 use actix_web::web::Data;
+use actix_web::web::Header;
 use actix_web::web::Path;
 use actix_web::web::Query;
-use actix_web::Error;
 use actix_web::HttpResponse;
 use cis_client::AsyncCisClientTrait;
 use cis_profile::schema::Display;
 use dino_park_gate::scope::ScopeAndUser;
 use dino_park_trust::Trust;
+use futures::TryStreamExt;
 use lru_time_cache::LruCache;
 use serde::Deserialize;
 use std::sync::Mutex;
@@ -36,24 +42,28 @@ fn default_size() -> String {
     "264".to_string()
 }
 
+#[allow(clippy::too_many_arguments)]
 async fn retrieve_avatar<T: AsyncCisClientTrait + Clone, L: Loader>(
     avatar_settings: Data<AvatarSettings>,
     loader: Data<L>,
     path: Path<Picture>,
     query: Query<PictureQuery>,
+    signature: Query<Signature>,
     scope_and_user: ScopeAndUser,
     cis_client: Data<T>,
     cache: Data<Mutex<LruCache<String, String>>>,
-) -> Result<HttpResponse, Error> {
+    if_none_match: Option<Header<IfNoneMatch>>,
+) -> Result<HttpResponse, ApiError> {
+    check_signature(&avatar_settings, &path.picture, &signature).map_err(failure::Error::from)?;
     let uuid = if scope_and_user.scope != Trust::Public {
         let cis_client = cis_client.into_inner();
         get_uuid(&scope_and_user.user_id, &*cis_client, &cache, query.own)
             .await
-            .map_err(error::ErrorNotFound)?
+            .map_err(|e| ApiError::Failed(Code::NotFound, e))?
     } else {
         None
     };
-    let b = retrieve_avatar_from_store(
+    let streamed = retrieve_avatar_from_store(
         &avatar_settings,
         &loader.into_inner(),
         &path.picture,
@@ -61,12 +71,31 @@ async fn retrieve_avatar<T: AsyncCisClientTrait + Clone, L: Loader>(
         Some(Display::from(scope_and_user.scope)),
         uuid,
     )
-    .await
-    .map_err(error::ErrorNotFound)?;
-    Ok(HttpResponse::Ok()
+    .await?;
+    let mut res = HttpResponse::Ok();
+    // without a stored hash there is nothing to compare against
+    if let Some(sha256) = streamed.sha256 {
+        let etag = EntityTag::new_strong(sha256);
+        let not_modified = match if_none_match.map(Header::into_inner) {
+            Some(IfNoneMatch::Any) => true,
+            Some(IfNoneMatch::Items(tags)) => tags.iter().any(|tag| tag.weak_eq(&etag)),
+            None => false,
+        };
+        if not_modified {
+            return Ok(HttpResponse::NotModified()
+                .insert_header(ETag(etag))
+                .finish());
+        }
+        res.insert_header(ETag(etag));
+    }
+    if let Some(size) = streamed.size {
+        res.no_chunking(size);
+    }
+    Ok(res
         .insert_header(ContentEncoding::Identity)
-        .insert_header(ContentType::png())
-        .body(b))
+        // only PNGs are stored
+        .insert_header((CONTENT_TYPE, ImageFormat::Png.mime_type()))
+        .streaming(streamed.stream.map_err(error::ErrorInternalServerError)))
 }
 
 pub fn retrieve_app<
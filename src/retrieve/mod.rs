@@ -1,3 +1,4 @@
 pub mod app;
 pub mod retriever;
+pub mod signature;
 mod uuid;
@@ -3,10 +3,18 @@
 //     level as their item
 #![allow(non_local_definitions)]
 
+use crate::retrieve::signature::verify;
+use crate::retrieve::signature::Signature;
+use crate::retrieve::signature::SignatureError;
+use crate::send::operations::SIZES;
+use crate::send::rehash::load_alias;
 use crate::settings::AvatarSettings;
+use crate::storage::is_not_found;
 use crate::storage::loader::Loader;
-use crate::storage::name::uuid_hash;
 use crate::storage::name::ExternalFileName;
+use crate::storage::name::ImageFormat;
+use crate::storage::name::InternalFileName;
+use crate::storage::Streamed;
 use cis_profile::schema::Display;
 use failure::Error;
 use log::warn;
@@ -14,53 +22,145 @@ use std::convert::TryFrom;
 use std::sync::Arc;
 
 #[derive(Fail, Debug, PartialEq)]
-enum RetrieveError {
+pub enum RetrieveError {
     #[fail(display = "Picture not found.")]
     NotFound,
+    #[fail(display = "Unknown picture size.")]
+    UnknownSize,
 }
 
-pub async fn retrieve_avatar_from_store(
+/// Once URLs are signed, pictures above `Display::Public` need a valid
+/// signature. Signatures of public pictures are checked if present.
+pub fn check_signature(
+    settings: &AvatarSettings,
+    picture: &str,
+    signature: &Signature,
+) -> Result<(), SignatureError> {
+    let signing = match &settings.url_signing {
+        Some(signing) => signing,
+        None => return Ok(()),
+    };
+    let public = match ExternalFileName::from_uri(picture) {
+        Ok(name) => name.internal.display == Display::Public,
+        // not found later on
+        Err(_) => return Ok(()),
+    };
+    if public && signature.expires.is_none() && signature.signature.is_none() {
+        return Ok(());
+    }
+    verify(signing, picture, signature)
+}
+
+/// The stored name of `picture` if it is visible in `scope`, the owner
+/// (`uuid`) sees all of them.
+fn visible_name(
     settings: &AvatarSettings,
-    loader: &Arc<impl Loader>,
     picture: &str,
-    size: &str,
     scope: Option<Display>,
     uuid: Option<String>,
-) -> Result<Vec<u8>, Error> {
-    let internal = match ExternalFileName::from_uri(picture) {
-        Ok(external_file_name) => external_file_name.internal,
+) -> Result<InternalFileName, RetrieveError> {
+    let external_file_name = match ExternalFileName::from_uri(picture) {
+        Ok(external_file_name) => external_file_name,
         Err(e) => {
             warn!("invalid file name: {}", e);
-            return Err(RetrieveError::NotFound.into());
+            return Err(RetrieveError::NotFound);
         }
     };
-    let scope = match uuid.map(|uuid| internal.uuid_hash == uuid_hash(&uuid)) {
+    // The format is chosen by whoever built the URL, while only PNGs are
+    // stored. Anything else would be served under the wrong type.
+    if external_file_name.format != ImageFormat::Png {
+        warn!(
+            "no {} pictures are stored",
+            external_file_name.format.extension()
+        );
+        return Err(RetrieveError::NotFound);
+    }
+    let secret = settings.uuid_hash_secret.as_deref();
+    let scope = match uuid.map(|uuid| external_file_name.belongs_to(&uuid, secret)) {
         Some(true) => Some(Display::Private),
         _ => scope,
     };
+    let internal = external_file_name.internal;
     if let Some(scope) = scope {
         if scope < Display::try_from(internal.display.as_str()).unwrap_or(Display::Public) {
-            return Err(RetrieveError::NotFound.into());
+            return Err(RetrieveError::NotFound);
+        }
+    }
+    Ok(internal)
+}
+
+/// Loads `size` of a picture, 528 falls back to 264 for older pictures.
+async fn load_size(
+    loader: &Arc<impl Loader>,
+    internal: &InternalFileName,
+    size: &str,
+    bucket: &str,
+) -> Result<Streamed, Error> {
+    let name = internal.to_string();
+    match loader.load_stream(&name, size, bucket).await {
+        Err(e) if size == "528" && is_not_found(&e) => {
+            loader.load_stream(&name, "264", bucket).await
         }
+        res => res,
+    }
+}
+
+pub async fn retrieve_avatar_from_store(
+    settings: &AvatarSettings,
+    loader: &Arc<impl Loader>,
+    picture: &str,
+    size: &str,
+    scope: Option<Display>,
+    uuid: Option<String>,
+) -> Result<Streamed, Error> {
+    // the size is used as storage prefix, only let known ones through
+    if !SIZES.contains(&size) {
+        return Err(RetrieveError::UnknownSize.into());
     }
-    let is_528 = size == "528";
-    let internal_s = internal.to_string();
-    match loader.load(&internal_s, size, &settings.s3_bucket).await {
-        Ok(data) => Ok(data),
-        Err(_) if is_528 => loader.load(&internal_s, "264", &settings.s3_bucket).await,
-        Err(e) => Err(e),
+    let internal = visible_name(settings, picture, scope, uuid)?;
+    let bucket = &settings.s3_bucket;
+    match load_size(loader, &internal, size, bucket).await {
+        // pictures moved to a keyed hash keep their old URLs
+        Err(e) if is_not_found(&e) && settings.uuid_hash_secret.is_some() => {
+            match load_alias(&internal.uuid_hash, bucket, loader).await? {
+                Some(uuid_hash) => {
+                    let moved = InternalFileName {
+                        uuid_hash,
+                        ..internal
+                    };
+                    load_size(loader, &moved, size, bucket).await
+                }
+                None => Err(e),
+            }
+        }
+        res => res,
     }
     .map_err(|e| {
         warn!("error loading picture: {}", e);
-        RetrieveError::NotFound.into()
+        // storage failures keep their own code
+        match is_not_found(&e) {
+            true => RetrieveError::NotFound.into(),
+            false => e,
+        }
     })
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::retrieve::signature::sign;
+    use crate::settings::UrlSigningSettings;
+    use crate::storage::memory::MemoryError;
+    use crate::storage::memory::MemoryStorage;
+    use actix_web::web::Query;
     use failure::format_err;
     use futures::future::BoxFuture;
+    use futures::TryStreamExt;
+
+    async fn len(streamed: Streamed) -> Result<usize, Error> {
+        let chunks: Vec<_> = streamed.stream.try_collect().await?;
+        Ok(chunks.iter().map(|chunk| chunk.len()).sum())
+    }
 
     struct DummyLoader {
         retrieve_528: bool,
@@ -70,14 +170,14 @@ mod test {
     impl Loader for DummyLoader {
         fn load(&self, name: &str, size: &str, _: &str) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
             let ret = if name != self.name {
-                Err(format_err!("404"))
+                Err(MemoryError::NotFound.into())
             } else {
                 match size {
                     "528" => {
                         if self.retrieve_528 {
                             Ok(vec![0; 528])
                         } else {
-                            Err(format_err!("no 528"))
+                            Err(MemoryError::NotFound.into())
                         }
                     }
                     "264" => Ok(vec![0; 264]),
@@ -93,12 +193,8 @@ mod test {
         let uuid = "9e697947-2990-4182-b080-533c16af4799";
         let display = &Display::Public;
 
-        let settings = AvatarSettings {
-            s3_bucket: String::from("testing"),
-            retrieve_by_id_path: String::from("/api/v666"),
-            picture_api_url: String::from("https://localhost"),
-        };
-        let picture = ExternalFileName::from_uuid_and_display(uuid, display);
+        let settings = AvatarSettings::for_testing();
+        let picture = ExternalFileName::from_uuid_and_display(uuid, display, None);
         let size = String::from("528");
 
         let loader = Arc::new(DummyLoader {
@@ -110,7 +206,7 @@ mod test {
             retrieve_avatar_from_store(&settings, &loader, &picture.filename(), &size, None, None)
                 .await?;
 
-        assert_eq!(avatar.len(), 264);
+        assert_eq!(len(avatar).await?, 264);
         Ok(())
     }
 
@@ -119,12 +215,8 @@ mod test {
         let uuid = "9e697947-2990-4182-b080-533c16af4799";
         let display = &Display::Public;
 
-        let settings = AvatarSettings {
-            s3_bucket: String::from("testing"),
-            retrieve_by_id_path: String::from("/api/v666"),
-            picture_api_url: String::from("https://localhost"),
-        };
-        let picture = ExternalFileName::from_uuid_and_display(uuid, display);
+        let settings = AvatarSettings::for_testing();
+        let picture = ExternalFileName::from_uuid_and_display(uuid, display, None);
         let loader = Arc::new(DummyLoader {
             retrieve_528: true,
             name: picture.internal.to_string(),
@@ -135,7 +227,7 @@ mod test {
             retrieve_avatar_from_store(&settings, &loader, &picture.filename(), &size, None, None)
                 .await?;
 
-        assert_eq!(avatar.len(), 528);
+        assert_eq!(len(avatar).await?, 528);
         Ok(())
     }
 
@@ -145,12 +237,8 @@ mod test {
         let wrong_uuid = "9e697947-2990-4182-b080-533c16af4790";
         let display = &Display::Staff;
 
-        let settings = AvatarSettings {
-            s3_bucket: String::from("testing"),
-            retrieve_by_id_path: String::from("/api/v666"),
-            picture_api_url: String::from("https://localhost"),
-        };
-        let picture = ExternalFileName::from_uuid_and_display(uuid, display);
+        let settings = AvatarSettings::for_testing();
+        let picture = ExternalFileName::from_uuid_and_display(uuid, display, None);
         let loader = Arc::new(DummyLoader {
             retrieve_528: true,
             name: picture.internal.to_string(),
@@ -179,12 +267,8 @@ mod test {
         let uuid = "9e697947-2990-4182-b080-533c16af4799";
         let display = &Display::Staff;
 
-        let settings = AvatarSettings {
-            s3_bucket: String::from("testing"),
-            retrieve_by_id_path: String::from("/api/v666"),
-            picture_api_url: String::from("https://localhost"),
-        };
-        let picture = ExternalFileName::from_uuid_and_display(uuid, display);
+        let settings = AvatarSettings::for_testing();
+        let picture = ExternalFileName::from_uuid_and_display(uuid, display, None);
         let loader = Arc::new(DummyLoader {
             retrieve_528: true,
             name: picture.internal.to_string(),
@@ -201,7 +285,115 @@ mod test {
         )
         .await?;
 
-        assert_eq!(avatar.len(), 528);
+        assert_eq!(len(avatar).await?, 528);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unknown_size_is_rejected() -> Result<(), Error> {
+        let uuid = "9e697947-2990-4182-b080-533c16af4799";
+        let display = &Display::Public;
+
+        let settings = AvatarSettings::for_testing();
+        let picture = ExternalFileName::from_uuid_and_display(uuid, display, None);
+        let loader = Arc::new(DummyLoader {
+            retrieve_528: true,
+            name: picture.internal.to_string(),
+        });
+
+        let res = retrieve_avatar_from_store(
+            &settings,
+            &loader,
+            &picture.filename(),
+            "../../etc",
+            None,
+            None,
+        )
+        .await;
+
+        assert_eq!(
+            res.err().unwrap().downcast::<RetrieveError>()?,
+            RetrieveError::UnknownSize
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_storage_failures_are_not_hidden() -> Result<(), Error> {
+        let settings = AvatarSettings::for_testing();
+        let picture = ExternalFileName::from_uuid_and_display("uuid", &Display::Public, None);
+        let loader = Arc::new(MemoryStorage::default().fail_load_prefix("528"));
+        loader.insert(
+            &picture.internal.to_string(),
+            "264",
+            "testing",
+            vec![0; 264],
+        );
+
+        let res =
+            retrieve_avatar_from_store(&settings, &loader, &picture.filename(), "528", None, None)
+                .await;
+        // no fallback to 264 either
+        assert!(matches!(
+            res.err().unwrap().downcast::<MemoryError>(),
+            Ok(MemoryError::Injected(_))
+        ));
+        let res =
+            retrieve_avatar_from_store(&settings, &loader, &picture.filename(), "100", None, None)
+                .await;
+        assert_eq!(
+            res.err().unwrap().downcast::<RetrieveError>()?,
+            RetrieveError::NotFound
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_png_names_are_visible() -> Result<(), Error> {
+        let settings = AvatarSettings::for_testing();
+        let mut picture = ExternalFileName::from_uuid_and_display("uuid", &Display::Public, None);
+        assert!(visible_name(&settings, &picture.filename(), None, None).is_ok());
+        picture.format = ImageFormat::Webp;
+        assert_eq!(
+            visible_name(&settings, &picture.filename(), None, None),
+            Err(RetrieveError::NotFound)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_check_signature() -> Result<(), Error> {
+        let mut settings = AvatarSettings::for_testing();
+        let staff =
+            ExternalFileName::from_uuid_and_display("uuid", &Display::Staff, None).filename();
+        let public =
+            ExternalFileName::from_uuid_and_display("uuid", &Display::Public, None).filename();
+        let unsigned = Signature::default();
+        assert_eq!(check_signature(&settings, &staff, &unsigned), Ok(()));
+
+        let signing = UrlSigningSettings {
+            secret: String::from("secret"),
+            ttl_seconds: 60,
+        };
+        settings.url_signing = Some(signing.clone());
+        assert_eq!(check_signature(&settings, &public, &unsigned), Ok(()));
+        // stripping the signature doesn't help
+        assert_eq!(
+            check_signature(&settings, &staff, &unsigned),
+            Err(SignatureError::Invalid)
+        );
+        let signed = Query::<Signature>::from_query(&sign(&signing, &staff))
+            .unwrap()
+            .into_inner();
+        assert_eq!(check_signature(&settings, &staff, &signed), Ok(()));
+        let forged = Signature {
+            expires: Some(i64::MAX),
+            signature: Some(String::from("forged")),
+        };
+        assert_eq!(
+            check_signature(&settings, &public, &forged),
+            Err(SignatureError::Invalid)
+        );
         Ok(())
     }
 }
@@ -0,0 +1,130 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
+use crate::settings::UrlSigningSettings;
+use chrono::Utc;
+use hmac::Hmac;
+use hmac::Mac;
+use hmac::NewMac;
+use serde::Deserialize;
+use sha2::Sha256;
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum SignatureError {
+    #[fail(display = "Missing or invalid signature.")]
+    Invalid,
+    #[fail(display = "Picture URL expired.")]
+    Expired,
+}
+
+/// Query parameters of a signed picture URL.
+#[derive(Deserialize, Clone, Debug, Default)]
+pub struct Signature {
+    pub expires: Option<i64>,
+    pub signature: Option<String>,
+}
+
+fn mac(settings: &UrlSigningSettings, picture: &str, expires: i64) -> Hmac<Sha256> {
+    // HMAC takes keys of any length
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(settings.secret.as_bytes()).expect("any key length");
+    mac.update(format!("{}:{}", picture, expires).as_bytes());
+    mac
+}
+
+/// The query string signing `picture` (the file name) until `ttl_seconds`
+/// from now.
+pub fn sign(settings: &UrlSigningSettings, picture: &str) -> String {
+    let expires = Utc::now().timestamp() + settings.ttl_seconds;
+    let signature = mac(settings, picture, expires).finalize().into_bytes();
+    format!(
+        "expires={}&signature={}",
+        expires,
+        base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+    )
+}
+
+/// Checks the signature before the expiry, an expired URL is only reported as
+/// such if it was signed by us.
+pub fn verify(
+    settings: &UrlSigningSettings,
+    picture: &str,
+    signature: &Signature,
+) -> Result<(), SignatureError> {
+    let (expires, tag) = match signature {
+        Signature {
+            expires: Some(expires),
+            signature: Some(tag),
+        } => (*expires, tag),
+        _ => return Err(SignatureError::Invalid),
+    };
+    let tag =
+        base64::decode_config(tag, base64::URL_SAFE_NO_PAD).map_err(|_| SignatureError::Invalid)?;
+    mac(settings, picture, expires)
+        .verify(&tag)
+        .map_err(|_| SignatureError::Invalid)?;
+    if expires < Utc::now().timestamp() {
+        return Err(SignatureError::Expired);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use actix_web::web::Query;
+
+    fn settings(ttl_seconds: i64) -> UrlSigningSettings {
+        UrlSigningSettings {
+            secret: String::from("secret"),
+            ttl_seconds,
+        }
+    }
+
+    fn parse(query: &str) -> Signature {
+        Query::<Signature>::from_query(query).unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let settings = settings(60);
+        let signature = parse(&sign(&settings, "abc.png"));
+        assert_eq!(verify(&settings, "abc.png", &signature), Ok(()));
+        assert_eq!(
+            verify(&settings, "abd.png", &signature),
+            Err(SignatureError::Invalid)
+        );
+        let later = Signature {
+            expires: signature.expires.map(|expires| expires + 1),
+            ..signature.clone()
+        };
+        assert_eq!(
+            verify(&settings, "abc.png", &later),
+            Err(SignatureError::Invalid)
+        );
+        assert_eq!(
+            verify(&settings, "abc.png", &Signature::default()),
+            Err(SignatureError::Invalid)
+        );
+        let other = UrlSigningSettings {
+            secret: String::from("other"),
+            ..settings
+        };
+        assert_eq!(
+            verify(&other, "abc.png", &signature),
+            Err(SignatureError::Invalid)
+        );
+    }
+
+    #[test]
+    fn test_expired() {
+        let settings = settings(-1);
+        let signature = parse(&sign(&settings, "abc.png"));
+        assert_eq!(
+            verify(&settings, "abc.png", &signature),
+            Err(SignatureError::Expired)
+        );
+    }
+}
@@ -1,12 +1,22 @@
 use crate::error::ApiError;
+use crate::send::history::HistoryEntry;
+use crate::send::sender::avatar_history;
 use crate::send::sender::change_display_level;
 use crate::send::sender::check_resize_store_intermediate;
 use crate::send::sender::delete_avatar;
+use crate::send::sender::purge_deleted_avatars;
+use crate::send::sender::restore_avatar;
+use crate::send::sender::restore_deleted_avatar;
 use crate::send::sender::store_intermediate;
 use crate::send::sender::PictureUrl;
+use crate::send::versions::list_versions;
+use crate::send::versions::load_version;
+use crate::send::versions::restore_version;
 use crate::settings::AvatarSettings;
 use crate::storage::loader::Loader;
 use crate::storage::saver::Saver;
+use crate::storage::Storage;
+use crate::storage::Version;
 use actix_multipart::Multipart;
 use actix_web::dev::HttpServiceFactory;
 use actix_web::web;
@@ -14,10 +24,11 @@ use actix_web::web::Bytes;
 use actix_web::web::Data;
 use actix_web::web::Json;
 use actix_web::web::Path;
+use actix_web::web::Query;
+use actix_web::HttpResponse;
 use cis_profile::schema::Display;
 use dino_park_guard::guard;
 use futures::StreamExt;
-use futures::TryStreamExt;
 use serde::Deserialize;
 use serde::Serialize;
 
@@ -48,6 +59,37 @@ pub struct ChangeDisplay {
     pub old_url: String,
 }
 
+#[derive(Deserialize)]
+pub struct DeleteQuery {
+    /// Skip the trash, e.g. for legal or GDPR deletion requests.
+    #[serde(default)]
+    pub force: bool,
+}
+
+#[derive(Serialize)]
+pub struct Purged {
+    pub purged: usize,
+}
+
+#[derive(Deserialize)]
+pub struct Restore {
+    pub ts: i64,
+    pub display: Option<Display>,
+    pub old_url: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VersionPath {
+    pub uuid: String,
+    pub version_id: String,
+}
+
+#[derive(Deserialize)]
+pub struct VersionQuery {
+    pub display: Display,
+    pub size: String,
+}
+
 #[guard(Authenticated)]
 async fn send_intermediate<S: Saver>(
     avatar_settings: Data<AvatarSettings>,
@@ -56,20 +98,16 @@ async fn send_intermediate<S: Saver>(
 ) -> Result<Json<Uuid>, ApiError> {
     if let Some(item) = multipart.next().await {
         let bucket = avatar_settings.s3_bucket.clone();
-        let field = item.map_err(|_| ApiError::MultipartError)?;
-        let buf = field
-            .try_fold(
-                Vec::<u8>::new(),
-                |mut acc: Vec<u8>, bytes: Bytes| async move {
-                    acc.extend(bytes.into_iter());
-                    Ok(acc)
-                },
-            )
-            .await
-            .map_err(|_| ApiError::MultipartError)?;
-        let uuid = store_intermediate(bucket, saver.into_inner(), buf)
-            .await
-            .map_err(ApiError::GenericBadRequest)?;
+        let mut field = item.map_err(|_| ApiError::MultipartError)?;
+        let mut buf = Vec::<u8>::new();
+        while let Some(bytes) = field.next().await {
+            let bytes: Bytes = bytes.map_err(|_| ApiError::MultipartError)?;
+            if buf.len() + bytes.len() > avatar_settings.max_upload_bytes {
+                return Err(ApiError::PayloadTooLarge(avatar_settings.max_upload_bytes));
+            }
+            buf.extend(bytes);
+        }
+        let uuid = store_intermediate(bucket, saver.into_inner(), buf).await?;
         Ok(Json(Uuid { uuid }))
     } else {
         Err(ApiError::MultipartError)
@@ -93,31 +131,90 @@ async fn send_save<S: Saver, L: Loader>(
     .await
     {
         Ok(picture_url) => Ok(Json(picture_url)),
-        Err(e) => Err(ApiError::GenericBadRequest(e)),
+        Err(e) => Err(e.into()),
     }
 }
 
-async fn delete<S: Saver>(
+async fn delete<S: Storage>(
     avatar_settings: Data<AvatarSettings>,
-    saver: Data<S>,
+    storage: Data<S>,
     path: Path<Uuid>,
+    query: Query<DeleteQuery>,
 ) -> Result<Json<String>, ApiError> {
-    match delete_avatar(&avatar_settings, &saver.into_inner(), &path.uuid).await {
+    match delete_avatar(
+        &avatar_settings,
+        &storage.into_inner(),
+        &path.uuid,
+        query.force,
+    )
+    .await
+    {
         Ok(_) => Ok(Json(String::default())),
-        Err(e) => Err(ApiError::GenericBadRequest(e)),
+        Err(e) => Err(e.into()),
     }
 }
 
-async fn update_display<S: Saver, L: Loader>(
+async fn undelete<S: Storage>(
+    avatar_settings: Data<AvatarSettings>,
+    storage: Data<S>,
+    path: Path<Uuid>,
+) -> Result<Json<PictureUrl>, ApiError> {
+    match restore_deleted_avatar(&avatar_settings, &storage.into_inner(), &path.uuid).await {
+        Ok(picture_url) => Ok(Json(picture_url)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn purge<S: Storage>(
+    avatar_settings: Data<AvatarSettings>,
+    storage: Data<S>,
+) -> Result<Json<Purged>, ApiError> {
+    match purge_deleted_avatars(&avatar_settings, &storage.into_inner()).await {
+        Ok(purged) => Ok(Json(Purged { purged })),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn history<L: Loader>(
+    avatar_settings: Data<AvatarSettings>,
+    loader: Data<L>,
+    path: Path<Uuid>,
+) -> Result<Json<Vec<HistoryEntry>>, ApiError> {
+    match avatar_history(&avatar_settings, &loader.into_inner(), &path.uuid).await {
+        Ok(entries) => Ok(Json(entries)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn restore<S: Saver, L: Loader>(
     avatar_settings: Data<AvatarSettings>,
     loader: Data<L>,
     saver: Data<S>,
     path: Path<Uuid>,
+    body: Json<Restore>,
+) -> Result<Json<PictureUrl>, ApiError> {
+    match restore_avatar(
+        &avatar_settings,
+        &saver.into_inner(),
+        &loader.into_inner(),
+        &path.uuid,
+        &body.into_inner(),
+    )
+    .await
+    {
+        Ok(picture_url) => Ok(Json(picture_url)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn update_display<S: Saver>(
+    avatar_settings: Data<AvatarSettings>,
+    saver: Data<S>,
+    path: Path<Uuid>,
     body: Json<ChangeDisplay>,
 ) -> Result<Json<PictureUrl>, ApiError> {
     match change_display_level(
         &avatar_settings,
-        &loader.into_inner(),
         &saver.into_inner(),
         &path.uuid,
         &body.into_inner(),
@@ -125,16 +222,87 @@ async fn update_display<S: Saver, L: Loader>(
     .await
     {
         Ok(picture_url) => Ok(Json(picture_url)),
-        Err(e) => Err(ApiError::GenericBadRequest(e)),
+        Err(e) => Err(e.into()),
     }
 }
 
-pub fn internal_send_app<S: Saver + Send + Sync + 'static, L: Loader + Send + Sync + 'static>(
-) -> impl HttpServiceFactory {
+async fn versions<S: Storage>(
+    avatar_settings: Data<AvatarSettings>,
+    storage: Data<S>,
+    path: Path<Uuid>,
+    query: Query<VersionQuery>,
+) -> Result<Json<Vec<Version>>, ApiError> {
+    match list_versions(
+        &avatar_settings,
+        &storage.into_inner(),
+        &path.uuid,
+        &query.display,
+        &query.size,
+    )
+    .await
+    {
+        Ok(versions) => Ok(Json(versions)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn version<L: Loader>(
+    avatar_settings: Data<AvatarSettings>,
+    loader: Data<L>,
+    path: Path<VersionPath>,
+    query: Query<VersionQuery>,
+) -> Result<HttpResponse, ApiError> {
+    match load_version(
+        &avatar_settings,
+        &loader.into_inner(),
+        &path.uuid,
+        &query.display,
+        &query.size,
+        &path.version_id,
+    )
+    .await
+    {
+        Ok(buf) => Ok(HttpResponse::Ok().content_type("image/png").body(buf)),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn restore_to_version<S: Storage>(
+    avatar_settings: Data<AvatarSettings>,
+    storage: Data<S>,
+    path: Path<VersionPath>,
+    query: Query<VersionQuery>,
+) -> Result<Json<String>, ApiError> {
+    match restore_version(
+        &avatar_settings,
+        &storage.into_inner(),
+        &path.uuid,
+        &query.display,
+        &query.size,
+        &path.version_id,
+    )
+    .await
+    {
+        Ok(_) => Ok(Json(String::default())),
+        Err(e) => Err(e.into()),
+    }
+}
+
+pub fn internal_send_app<S: Storage + 'static>() -> impl HttpServiceFactory {
     web::scope("/internal")
         .service(web::resource("/delete/{uuid}").route(web::delete().to(delete::<S>)))
-        .service(web::resource("/save/{uuid}").route(web::post().to(send_save::<S, L>)))
-        .service(web::resource("/display/{uuid}").route(web::post().to(update_display::<S, L>)))
+        .service(web::resource("/save/{uuid}").route(web::post().to(send_save::<S, S>)))
+        .service(web::resource("/display/{uuid}").route(web::post().to(update_display::<S>)))
+        .service(web::resource("/trash/purge").route(web::post().to(purge::<S>)))
+        .service(web::resource("/trash/{uuid}/restore").route(web::post().to(undelete::<S>)))
+        .service(web::resource("/history/{uuid}").route(web::get().to(history::<S>)))
+        .service(web::resource("/history/{uuid}/restore").route(web::post().to(restore::<S, S>)))
+        .service(web::resource("/versions/{uuid}").route(web::get().to(versions::<S>)))
+        .service(web::resource("/versions/{uuid}/{version_id}").route(web::get().to(version::<S>)))
+        .service(
+            web::resource("/versions/{uuid}/{version_id}/restore")
+                .route(web::post().to(restore_to_version::<S>)),
+        )
 }
 
 pub fn send_app<S: Saver + Send + Sync + 'static, L: Loader + Send + Sync + 'static>(
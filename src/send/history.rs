@@ -0,0 +1,344 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
+use crate::send::operations::LARGE;
+use crate::send::operations::MEDIUM;
+use crate::send::operations::RAW;
+use crate::send::operations::SIZES;
+use crate::send::operations::SMALL;
+use crate::send::operations::XLARGE;
+use crate::send::resize::Avatars;
+use crate::storage::is_not_found;
+use crate::storage::loader::Loader;
+use crate::storage::name::ExternalFileName;
+use crate::storage::name::HistoryFileName;
+use crate::storage::saver::Saver;
+use crate::storage::Storage;
+use cis_profile::schema::Display;
+use failure::Error;
+use futures::future;
+use log::info;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::sync::Arc;
+
+const HISTORY: &str = "history";
+
+#[derive(Debug, Fail)]
+pub enum HistoryError {
+    #[fail(display = "unknown avatar version")]
+    UnknownVersion,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct HistoryEntry {
+    pub ts: i64,
+    pub display: Display,
+}
+
+pub fn history_prefix(size: &str) -> String {
+    format!("{}/{}", HISTORY, size)
+}
+
+fn index_name(uuid_hash: &str) -> String {
+    format!("{}.json", uuid_hash)
+}
+
+fn history_name(uuid_hash: &str, ts: i64) -> String {
+    HistoryFileName {
+        uuid_hash: uuid_hash.to_owned(),
+        ts,
+    }
+    .to_string()
+}
+
+/// Returns all archived versions, newest first. A missing index means there is
+/// no history yet, any other failure is passed on so the index is not
+/// overwritten with an empty one.
+pub async fn load_history(
+    uuid_hash: &str,
+    bucket: &str,
+    loader: &Arc<impl Loader>,
+) -> Result<Vec<HistoryEntry>, Error> {
+    match loader.load(&index_name(uuid_hash), HISTORY, bucket).await {
+        Ok(buf) => Ok(serde_json::from_slice(&buf)?),
+        Err(e) if is_not_found(&e) => {
+            info!("no history for {}", uuid_hash);
+            Ok(vec![])
+        }
+        Err(e) => Err(e),
+    }
+}
+
+async fn save_history(
+    uuid_hash: &str,
+    bucket: &str,
+    entries: &[HistoryEntry],
+    saver: &Arc<impl Saver>,
+) -> Result<(), Error> {
+    let buf = serde_json::to_vec(entries)?;
+    saver
+        .save(&index_name(uuid_hash), HISTORY, bucket, buf)
+        .await
+}
+
+async fn delete_version(
+    uuid_hash: &str,
+    ts: i64,
+    bucket: &str,
+    saver: &Arc<impl Saver>,
+) -> Result<(), Error> {
+    let name = history_name(uuid_hash, ts);
+    future::try_join5(
+        saver.delete(&name, &history_prefix(RAW), bucket),
+        saver.delete(&name, &history_prefix(XLARGE), bucket),
+        saver.delete(&name, &history_prefix(LARGE), bucket),
+        saver.delete(&name, &history_prefix(MEDIUM), bucket),
+        saver.delete(&name, &history_prefix(SMALL), bucket),
+    )
+    .await?;
+    Ok(())
+}
+
+/// Loads all sizes of the archived version `ts`.
+pub async fn load_version(
+    uuid_hash: &str,
+    ts: i64,
+    bucket: &str,
+    loader: &Arc<impl Loader>,
+) -> Result<Avatars, Error> {
+    let name = history_name(uuid_hash, ts);
+    let (raw, x528, x264, x100, x40) = future::try_join5(
+        loader.load(&name, &history_prefix(RAW), bucket),
+        loader.load(&name, &history_prefix(XLARGE), bucket),
+        loader.load(&name, &history_prefix(LARGE), bucket),
+        loader.load(&name, &history_prefix(MEDIUM), bucket),
+        loader.load(&name, &history_prefix(SMALL), bucket),
+    )
+    .await
+    .map_err(|e| {
+        warn!("unable to load version {} for {}: {}", ts, uuid_hash, e);
+        HistoryError::UnknownVersion
+    })?;
+    Ok(Avatars {
+        raw,
+        x528,
+        x264,
+        x100,
+        x40,
+    })
+}
+
+/// Copies all sizes of `old` into the history and drops everything beyond the
+/// `keep` newest versions.
+pub async fn archive(
+    old: &ExternalFileName,
+    bucket: &str,
+    keep: usize,
+    saver: &Arc<impl Saver>,
+    loader: &Arc<impl Loader>,
+) -> Result<(), Error> {
+    if keep == 0 {
+        return Ok(());
+    }
+    let uuid_hash = &old.internal.uuid_hash;
+    let internal = old.internal.to_string();
+    let name = history_name(uuid_hash, old.ts);
+    future::try_join5(
+        saver.copy(&internal, RAW, &name, &history_prefix(RAW), bucket),
+        saver.copy(&internal, XLARGE, &name, &history_prefix(XLARGE), bucket),
+        saver.copy(&internal, LARGE, &name, &history_prefix(LARGE), bucket),
+        saver.copy(&internal, MEDIUM, &name, &history_prefix(MEDIUM), bucket),
+        saver.copy(&internal, SMALL, &name, &history_prefix(SMALL), bucket),
+    )
+    .await?;
+
+    let mut entries = load_history(uuid_hash, bucket, loader).await?;
+    entries.retain(|entry| entry.ts != old.ts);
+    entries.push(HistoryEntry {
+        ts: old.ts,
+        display: old.internal.display.clone(),
+    });
+    entries.sort_by_key(|entry| Reverse(entry.ts));
+    let expired = entries.split_off(keep.min(entries.len()));
+    save_history(uuid_hash, bucket, &entries, saver).await?;
+    for entry in expired {
+        delete_version(uuid_hash, entry.ts, bucket, saver).await?;
+    }
+    info!("archived version {} for {}", old.ts, uuid_hash);
+    Ok(())
+}
+
+/// Removes the archived version `ts` from the history.
+pub async fn forget(
+    uuid_hash: &str,
+    ts: i64,
+    bucket: &str,
+    saver: &Arc<impl Saver>,
+    loader: &Arc<impl Loader>,
+) -> Result<(), Error> {
+    let mut entries = load_history(uuid_hash, bucket, loader).await?;
+    entries.retain(|entry| entry.ts != ts);
+    save_history(uuid_hash, bucket, &entries, saver).await?;
+    delete_version(uuid_hash, ts, bucket, saver).await
+}
+
+/// Removes all archived versions including the index. Versions missing from
+/// the index are removed as well.
+pub async fn purge(
+    uuid_hash: &str,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<(), Error> {
+    let version_prefix = format!("{}_", uuid_hash);
+    for size in SIZES.iter() {
+        let prefix = history_prefix(size);
+        let names = storage
+            .list(&prefix, bucket)
+            .await?
+            .into_iter()
+            .filter(|name| name.starts_with(&version_prefix))
+            .collect::<Vec<_>>();
+        if !names.is_empty() {
+            storage.delete_many(&names, &prefix, bucket).await?;
+        }
+    }
+    storage
+        .delete(&index_name(uuid_hash), HISTORY, bucket)
+        .await
+}
+
+/// Moves the history of `from` to the uuid hash `to`. Returns the number of
+/// moved versions.
+pub async fn rehash(
+    from: &str,
+    to: &str,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<usize, Error> {
+    let entries = load_history(from, bucket, storage).await?;
+    if entries.is_empty() {
+        return Ok(0);
+    }
+    for entry in &entries {
+        let (old, new) = (history_name(from, entry.ts), history_name(to, entry.ts));
+        for size in SIZES.iter() {
+            let prefix = history_prefix(size);
+            if storage.exists(&old, &prefix, bucket).await? {
+                storage.rename(&old, &prefix, &new, &prefix, bucket).await?;
+            }
+        }
+    }
+    save_history(to, bucket, &entries, storage).await?;
+    storage.delete(&index_name(from), HISTORY, bucket).await?;
+    Ok(entries.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::name::uuid_hash;
+    use crate::storage::name::HashVersion;
+    use crate::storage::name::ImageFormat;
+    use crate::storage::name::InternalFileName;
+
+    async fn store_live(store: &Arc<MemoryStorage>, uuid: &str, ts: i64) -> ExternalFileName {
+        let file_name = ExternalFileName {
+            internal: InternalFileName::from_uuid_and_display(uuid, &Display::Staff, None),
+            ts,
+            version: HashVersion::current(None),
+            format: ImageFormat::Png,
+            flags: 0,
+        };
+        for size in SIZES.iter() {
+            Storage::save(
+                &**store,
+                &file_name.internal.to_string(),
+                size,
+                "testing",
+                ts.to_be_bytes().to_vec(),
+            )
+            .await
+            .unwrap();
+        }
+        file_name
+    }
+
+    #[tokio::test]
+    async fn test_archive_keeps_newest_versions() -> Result<(), Error> {
+        let uuid = "9e697947-2990-4182-b080-533c16af4799";
+        let store = Arc::new(MemoryStorage::default());
+        for ts in 1..=3 {
+            let old = store_live(&store, uuid, ts).await;
+            archive(&old, "testing", 2, &store, &store).await?;
+        }
+        let uuid_hash = uuid_hash(uuid, None);
+        let entries = load_history(&uuid_hash, "testing", &store).await?;
+        assert_eq!(
+            entries.iter().map(|entry| entry.ts).collect::<Vec<_>>(),
+            vec![3, 2]
+        );
+        assert!(load_version(&uuid_hash, 1, "testing", &store)
+            .await
+            .is_err());
+        let avatars = load_version(&uuid_hash, 2, "testing", &store).await?;
+        assert_eq!(avatars.x40, 2i64.to_be_bytes().to_vec());
+
+        forget(&uuid_hash, 2, "testing", &store, &store).await?;
+        let entries = load_history(&uuid_hash, "testing", &store).await?;
+        assert_eq!(entries.len(), 1);
+        assert!(load_version(&uuid_hash, 2, "testing", &store)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_purge_removes_unindexed_versions() -> Result<(), Error> {
+        let uuid = "9e697947-2990-4182-b080-533c16af4799";
+        let store = Arc::new(MemoryStorage::default());
+        let old = store_live(&store, uuid, 1).await;
+        archive(&old, "testing", 2, &store, &store).await?;
+        let uuid_hash = uuid_hash(uuid, None);
+        // a version which never made it into the index
+        let orphan = history_name(&uuid_hash, 2);
+        Storage::save(&*store, &orphan, &history_prefix(SMALL), "testing", vec![2]).await?;
+        Storage::save(
+            &*store,
+            "other_1.png",
+            &history_prefix(SMALL),
+            "testing",
+            vec![3],
+        )
+        .await?;
+
+        purge(&uuid_hash, "testing", &store).await?;
+        assert!(load_history(&uuid_hash, "testing", &store)
+            .await?
+            .is_empty());
+        assert_eq!(
+            Storage::list(&*store, &history_prefix(SMALL), "testing").await?,
+            vec!["other_1.png"]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_history_is_kept() -> Result<(), Error> {
+        let uuid = "9e697947-2990-4182-b080-533c16af4799";
+        let store = Arc::new(MemoryStorage::default().fail_load_prefix(HISTORY));
+        let uuid_hash = uuid_hash(uuid, None);
+        store.insert(&index_name(&uuid_hash), HISTORY, "testing", b"[]".to_vec());
+        assert!(load_history(&uuid_hash, "testing", &store).await.is_err());
+
+        let old = store_live(&store, uuid, 1).await;
+        assert!(archive(&old, "testing", 2, &store, &store).await.is_err());
+        assert!(Storage::exists(&*store, &index_name(&uuid_hash), HISTORY, "testing").await?);
+        Ok(())
+    }
+}
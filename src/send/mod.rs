@@ -1,4 +1,8 @@
 pub mod app;
+pub mod history;
 pub mod operations;
+pub mod rehash;
 pub mod resize;
 pub mod sender;
+pub mod trash;
+pub mod versions;
@@ -1,4 +1,6 @@
+use crate::send::history;
 use crate::send::resize::Avatars;
+use crate::send::trash;
 use crate::storage::loader::Loader;
 use crate::storage::saver::Saver;
 use failure::Error;
@@ -7,11 +9,27 @@ use futures::future;
 use log::warn;
 use std::sync::Arc;
 
-const RAW: &str = "raw";
-const XLARGE: &str = "528";
-const LARGE: &str = "264";
-const MEDIUM: &str = "100";
-const SMALL: &str = "40";
+pub const RAW: &str = "raw";
+pub const XLARGE: &str = "528";
+pub const LARGE: &str = "264";
+pub const MEDIUM: &str = "100";
+pub const SMALL: &str = "40";
+/// All sizes stored for an avatar.
+pub const SIZES: [&str; 5] = [RAW, XLARGE, LARGE, MEDIUM, SMALL];
+
+/// Every prefix derivatives are stored under: live, archived and trashed.
+pub fn derivative_prefixes() -> Vec<String> {
+    SIZES
+        .iter()
+        .flat_map(|size| {
+            vec![
+                size.to_string(),
+                history::history_prefix(size),
+                trash::trash_prefix(size),
+            ]
+        })
+        .collect()
+}
 
 pub async fn delete(name: &str, bucket: &str, saver: &Arc<impl Saver>) -> Result<(), Error> {
     future::try_join5(
@@ -65,12 +83,7 @@ pub async fn delete_many(
     Ok(())
 }
 
-pub async fn save(
-    avatars: Avatars,
-    name: &str,
-    bucket: &str,
-    saver: &Arc<impl Saver>,
-) -> Result<(), Error> {
+fn sizes(avatars: Avatars) -> Vec<(&'static str, Vec<u8>)> {
     let Avatars {
         raw,
         x528,
@@ -78,60 +91,178 @@ pub async fn save(
         x100,
         x40,
     } = avatars;
-    future::try_join5(
-        saver.save(name, RAW, bucket, raw),
-        saver.save(name, XLARGE, bucket, x528),
-        saver.save(name, LARGE, bucket, x264),
-        saver.save(name, MEDIUM, bucket, x100),
-        saver.save(name, SMALL, bucket, x40),
+    vec![
+        (RAW, raw),
+        (XLARGE, x528),
+        (LARGE, x264),
+        (MEDIUM, x100),
+        (SMALL, x40),
+    ]
+}
+
+pub async fn load(name: &str, bucket: &str, loader: &Arc<impl Loader>) -> Result<Avatars, Error> {
+    let (raw, x528, x264, x100, x40) = future::try_join5(
+        loader.load(name, RAW, bucket),
+        loader.load(name, XLARGE, bucket),
+        loader.load(name, LARGE, bucket),
+        loader.load(name, MEDIUM, bucket),
+        loader.load(name, SMALL, bucket),
     )
     .await?;
-    Ok(())
+    Ok(Avatars {
+        raw,
+        x528,
+        x264,
+        x100,
+        x40,
+    })
+}
+
+/// Saves all sizes or none of them.
+pub async fn save(
+    avatars: Avatars,
+    name: &str,
+    bucket: &str,
+    saver: &Arc<impl Saver>,
+) -> Result<(), Error> {
+    replace(avatars, None, name, bucket, saver).await
 }
 
+/// Saves all sizes or none of them. If saving any size fails, all sizes are
+/// rolled back to `previous` or deleted if there was nothing to replace.
+pub async fn replace(
+    avatars: Avatars,
+    previous: Option<Avatars>,
+    name: &str,
+    bucket: &str,
+    saver: &Arc<impl Saver>,
+) -> Result<(), Error> {
+    let results = future::join_all(
+        sizes(avatars)
+            .into_iter()
+            .map(|(size, buf)| saver.save(name, size, bucket, buf)),
+    )
+    .await;
+    let err = match results.into_iter().find_map(Result::err) {
+        Some(e) => e,
+        None => return Ok(()),
+    };
+    warn!("unable to save {}, rolling back: {}", name, err);
+    let rollback = match previous {
+        Some(previous) => {
+            future::join_all(
+                sizes(previous)
+                    .into_iter()
+                    .map(|(size, buf)| saver.save(name, size, bucket, buf)),
+            )
+            .await
+        }
+        None => future::join_all(SIZES.iter().map(|size| saver.delete(name, size, bucket))).await,
+    };
+    for e in rollback.into_iter().filter_map(Result::err) {
+        warn!("unable to roll back {}: {}", name, e);
+    }
+    Err(err)
+}
+
+/// Moves all sizes to `new_name`. If a required size cannot be moved, the
+/// sizes moved so far are moved back.
 pub async fn rename(
     old_name: &str,
     new_name: &str,
     bucket: &str,
     saver: &Arc<impl Saver>,
-    loader: &Arc<impl Loader>,
 ) -> Result<(), Error> {
     if old_name == new_name {
         return Ok(());
     }
-    future::try_join5(
-        rename_one(old_name, new_name, RAW, bucket, saver, loader).map(|r| match r {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                warn!("unable to rename RAW picture: {}", e);
-                Ok::<_, Error>(())
-            }
-        }),
-        rename_one(old_name, new_name, XLARGE, bucket, saver, loader).map(|r| match r {
-            Ok(_) => Ok(()),
-            Err(e) => {
-                warn!("unable to rename XLARGE picture: {}", e);
-                Ok::<_, Error>(())
+    let results = future::join_all(
+        SIZES
+            .iter()
+            .map(|size| saver.rename(old_name, size, new_name, size, bucket)),
+    )
+    .await;
+    let mut renamed = vec![];
+    let mut err = None;
+    for (size, result) in SIZES.iter().zip(results) {
+        match result {
+            Ok(_) => renamed.push(size),
+            // Older avatars were stored without these sizes.
+            Err(e) if *size == RAW || *size == XLARGE => {
+                warn!("unable to rename {} picture: {}", size, e)
             }
-        }),
-        rename_one(old_name, new_name, LARGE, bucket, saver, loader),
-        rename_one(old_name, new_name, MEDIUM, bucket, saver, loader),
-        rename_one(old_name, new_name, SMALL, bucket, saver, loader),
+            Err(e) => err = err.or(Some(e)),
+        }
+    }
+    let err = match err {
+        Some(e) => e,
+        None => return Ok(()),
+    };
+    warn!("unable to rename {}, rolling back: {}", old_name, err);
+    let rollback = future::join_all(
+        renamed
+            .iter()
+            .map(|size| saver.rename(new_name, size, old_name, size, bucket)),
     )
-    .await?;
-    Ok(())
+    .await;
+    for e in rollback.into_iter().filter_map(Result::err) {
+        warn!("unable to roll back {}: {}", old_name, e);
+    }
+    Err(err)
 }
 
-async fn rename_one(
-    old_name: &str,
-    new_name: &str,
-    size: &str,
-    bucket: &str,
-    saver: &Arc<impl Saver>,
-    loader: &Arc<impl Loader>,
-) -> Result<(), Error> {
-    let buf = loader.load(old_name, size, bucket).await?;
-    saver.save(new_name, size, bucket, buf).await?;
-    saver.delete(old_name, size, bucket).await?;
-    Ok(())
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    fn avatars(content: u8) -> Avatars {
+        Avatars {
+            raw: vec![content],
+            x528: vec![content],
+            x264: vec![content],
+            x100: vec![content],
+            x40: vec![content],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_failed_save_leaves_nothing_behind() -> Result<(), Error> {
+        let store = Arc::new(MemoryStorage::default().fail_prefix(SMALL));
+        assert!(save(avatars(1), "name", "testing", &store).await.is_err());
+        assert!(store.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_replace_rolls_back_to_previous() -> Result<(), Error> {
+        let store = Arc::new(MemoryStorage::default().fail_prefix(MEDIUM));
+        for size in SIZES.iter() {
+            store.insert("name", size, "testing", vec![1]);
+        }
+        let previous = load("name", "testing", &store).await?;
+        assert!(
+            replace(avatars(2), Some(previous), "name", "testing", &store)
+                .await
+                .is_err()
+        );
+        for size in &[RAW, XLARGE, LARGE, SMALL] {
+            assert_eq!(store.load("name", size, "testing").await?, vec![1]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_rename_rolls_back() -> Result<(), Error> {
+        let store = Arc::new(MemoryStorage::default().fail_prefix(SMALL));
+        for size in SIZES.iter() {
+            store.insert("old", size, "testing", vec![1]);
+        }
+        assert!(rename("old", "new", "testing", &store).await.is_err());
+        for size in SIZES.iter() {
+            assert_eq!(store.load("old", size, "testing").await?, vec![1]);
+            assert!(store.load("new", size, "testing").await.is_err());
+        }
+        Ok(())
+    }
 }
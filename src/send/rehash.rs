@@ -0,0 +1,208 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
+use crate::send::history;
+use crate::send::operations::SIZES;
+use crate::send::trash;
+use crate::send::trash::trash_prefix;
+use crate::settings::AvatarSettings;
+use crate::storage::is_not_found;
+use crate::storage::loader::Loader;
+use crate::storage::name::sha256_uuid_hash;
+use crate::storage::name::versioned_uuid_hash;
+use crate::storage::name::HashVersion;
+use crate::storage::name::InternalFileName;
+use crate::storage::name::DISPLAY_LEVELS;
+use crate::storage::Storage;
+use failure::Error;
+use log::info;
+use std::sync::Arc;
+
+/// Maps plain uuid hashes to the keyed ones they were moved to, so picture
+/// URLs stored in profiles keep working.
+const ALIASES: &str = "aliases";
+
+#[derive(Debug, Fail)]
+pub enum RehashError {
+    #[fail(display = "no uuid_hash_secret configured")]
+    MissingSecret,
+}
+
+/// Moves everything stored for `uuid` from its plain SHA-256 hash to the
+/// keyed one. Returns the number of moved objects.
+pub async fn rehash_uuid(
+    uuid: &str,
+    settings: &AvatarSettings,
+    storage: &Arc<impl Storage>,
+) -> Result<usize, Error> {
+    let secret = settings.uuid_hash_secret.as_deref();
+    let to =
+        versioned_uuid_hash(HashVersion::V2, uuid, secret).ok_or(RehashError::MissingSecret)?;
+    rehash(&sha256_uuid_hash(uuid), &to, &settings.s3_bucket, storage).await
+}
+
+/// The uuid hash the objects of `from` were moved to, `None` if they weren't.
+pub async fn load_alias(
+    from: &str,
+    bucket: &str,
+    loader: &Arc<impl Loader>,
+) -> Result<Option<String>, Error> {
+    match loader.load(from, ALIASES, bucket).await {
+        Ok(buf) => Ok(Some(String::from_utf8(buf)?)),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+/// Moves the live and trashed derivatives of every display level, the
+/// archived versions and the index entries of uuid hash `from` to `to`.
+/// Objects already moved are skipped, so this can run again after a failure.
+pub async fn rehash(
+    from: &str,
+    to: &str,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<usize, Error> {
+    // first, so whatever moves is found under the old names
+    storage
+        .save(from, ALIASES, bucket, to.as_bytes().to_vec())
+        .await?;
+    let mut moved = 0;
+    for display in DISPLAY_LEVELS.iter() {
+        let name = |uuid_hash: &str| {
+            InternalFileName {
+                uuid_hash: uuid_hash.to_owned(),
+                display: display.clone(),
+            }
+            .to_string()
+        };
+        let (old, new) = (name(from), name(to));
+        for size in SIZES.iter() {
+            for prefix in &[size.to_string(), trash_prefix(size)] {
+                if storage.exists(&old, prefix, bucket).await? {
+                    storage.rename(&old, prefix, &new, prefix, bucket).await?;
+                    moved += 1;
+                }
+            }
+        }
+    }
+    moved += history::rehash(from, to, bucket, storage).await?;
+    trash::rehash_entry(from, to, bucket, storage).await?;
+    info!("moved {} objects from {} to {}", moved, from, to);
+    Ok(moved)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::retrieve::retriever::retrieve_avatar_from_store;
+    use crate::send::history::archive;
+    use crate::send::history::load_history;
+    use crate::send::operations::SMALL;
+    use crate::settings::EncryptionSettings;
+    use crate::storage::encrypted::EncryptedStorage;
+    use crate::storage::encrypted::Keyring;
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::name::keyed_uuid_hash;
+    use crate::storage::name::ExternalFileName;
+    use crate::storage::name::ImageFormat;
+    use cis_profile::schema::Display;
+    use futures::TryStreamExt;
+
+    #[tokio::test]
+    async fn test_rehash_moves_everything() -> Result<(), Error> {
+        let uuid = "9e697947-2990-4182-b080-533c16af4799";
+        let (from, to) = (sha256_uuid_hash(uuid), keyed_uuid_hash(b"secret", uuid));
+        let store = Arc::new(MemoryStorage::default());
+        let staff = ExternalFileName {
+            internal: InternalFileName {
+                uuid_hash: from.clone(),
+                display: Display::Staff,
+            },
+            ts: 1,
+            version: HashVersion::V1,
+            format: ImageFormat::Png,
+            flags: 0,
+        };
+        for size in SIZES.iter() {
+            store.insert(&staff.internal.to_string(), size, "testing", vec![1]);
+        }
+        archive(&staff, "testing", 5, &store, &store).await?;
+        trash::trash(&from, "testing", &store).await?;
+
+        // trashed sizes plus one archived version
+        assert_eq!(rehash(&from, &to, "testing", &store).await?, 6);
+        let name = InternalFileName {
+            uuid_hash: to.clone(),
+            display: Display::Staff,
+        };
+        assert!(
+            store
+                .exists(&name.to_string(), &trash_prefix(SMALL), "testing")
+                .await?
+        );
+        assert!(load_history(&from, "testing", &store).await?.is_empty());
+        assert_eq!(load_history(&to, "testing", &store).await?.len(), 1);
+        assert!(trash::load_entry(&from, "testing", &store).await?.is_none());
+        assert!(trash::load_entry(&to, "testing", &store).await?.is_some());
+
+        assert_eq!(rehash(&from, &to, "testing", &store).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rehash_reseals_for_the_new_owner() -> Result<(), Error> {
+        let uuid = "9e697947-2990-4182-b080-533c16af4799";
+        let encryption = EncryptionSettings {
+            active_key_id: String::from("a"),
+            keys: vec![(String::from("a"), base64::encode([1; 32]))]
+                .into_iter()
+                .collect(),
+            allow_plaintext: false,
+        };
+        let store = Arc::new(EncryptedStorage::new(
+            MemoryStorage::default(),
+            Keyring::from_settings(&encryption)?,
+        ));
+        let settings = AvatarSettings {
+            uuid_hash_secret: Some(String::from("secret")),
+            ..AvatarSettings::for_testing()
+        };
+        let old = ExternalFileName::from_uuid_and_display(uuid, &Display::Staff, None);
+        for size in SIZES.iter() {
+            Storage::save(&*store, &old.internal.to_string(), size, "testing", vec![1]).await?;
+        }
+        archive(&old, "testing", 5, &store, &store).await?;
+
+        assert_eq!(rehash_uuid(uuid, &settings, &store).await?, 6);
+        let to = keyed_uuid_hash(b"secret", uuid);
+        let new = InternalFileName {
+            uuid_hash: to.clone(),
+            display: Display::Staff,
+        }
+        .to_string();
+        assert_eq!(
+            Storage::load(&*store, &new, SMALL, "testing").await?,
+            vec![1]
+        );
+        assert!(Storage::load(store.inner(), &new, SMALL, "testing")
+            .await?
+            .starts_with(b"FSLE"));
+        assert_eq!(
+            history::load_version(&to, old.ts, "testing", &store)
+                .await?
+                .x40,
+            vec![1]
+        );
+
+        // the URL in the profile still resolves
+        let streamed =
+            retrieve_avatar_from_store(&settings, &store, &old.filename(), SMALL, None, None)
+                .await?;
+        let chunks: Vec<_> = streamed.stream.try_collect().await?;
+        assert_eq!(chunks.concat(), vec![1]);
+        Ok(())
+    }
+}
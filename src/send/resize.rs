@@ -1,11 +1,23 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
 use byteorder::WriteBytesExt;
-use failure::format_err;
 use failure::Error;
 use image::imageops::FilterType;
 use image::DynamicImage;
 use image::GenericImageView;
 use log::debug;
 
+#[derive(Debug, Fail, PartialEq)]
+pub enum ImageError {
+    #[fail(display = "invalid image supplied, only png is supported")]
+    UnsupportedFormat,
+    #[fail(display = "wrong aspect ratio: {}", _0)]
+    AspectRatio(f64),
+}
+
 pub struct Avatars {
     pub raw: Vec<u8>,
     pub x528: Vec<u8>,
@@ -18,14 +30,14 @@ impl Avatars {
     pub fn new(buf: Vec<u8>) -> Result<Self, Error> {
         match image::guess_format(&buf) {
             Ok(image::ImageFormat::Png) => (),
-            _ => return Err(format_err!("invalid image supplied, only png is supported")),
+            _ => return Err(ImageError::UnsupportedFormat.into()),
         }
 
         let img = image::load_from_memory_with_format(&buf, image::ImageFormat::Png)?;
         let (w, h) = img.dimensions();
         let ratio = f64::from(w) / f64::from(h);
         if !(0.95..=1.05).contains(&ratio) {
-            return Err(format_err!("wrong aspect ratio: {}", ratio));
+            return Err(ImageError::AspectRatio(ratio).into());
         }
 
         // Copy the necessary data from the original image the image crate does not pick up manually
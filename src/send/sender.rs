@@ -3,74 +3,208 @@
 //     level as their item
 #![allow(non_local_definitions)]
 
+use crate::retrieve::signature::sign;
 use crate::send::app::ChangeDisplay;
+use crate::send::app::Restore;
 use crate::send::app::Save;
+use crate::send::history::archive;
+use crate::send::history::forget;
+use crate::send::history::load_history;
+use crate::send::history::load_version;
+use crate::send::history::HistoryEntry;
+use crate::send::history::HistoryError;
 use crate::send::operations::delete;
 use crate::send::operations::delete_many;
+use crate::send::operations::load;
 use crate::send::operations::rename;
+use crate::send::operations::replace;
 use crate::send::operations::save;
 use crate::send::resize::Avatars;
+use crate::send::trash;
 use crate::settings::AvatarSettings;
+use crate::storage::is_not_found;
 use crate::storage::loader::Loader;
+use crate::storage::name::uuid_hashes;
 use crate::storage::name::ExternalFileName;
 use crate::storage::name::InternalFileName;
+use crate::storage::name::DISPLAY_LEVELS;
 use crate::storage::saver::Saver;
+use crate::storage::Storage;
+use crate::storage::TMP;
+use chrono::Duration;
 use cis_profile::schema::Display;
 use failure::Error;
 use log::info;
 use log::warn;
 use serde::Serialize;
+use std::cmp::Reverse;
 use std::sync::Arc;
 
 #[derive(Debug, Fail)]
 pub enum SaveError {
     #[fail(display = "uuid mismatch")]
     UuidMismatch,
+    #[fail(display = "nothing to restore")]
+    NothingToRestore,
+    #[fail(display = "no such intermediate picture")]
+    MissingIntermediate,
+    #[fail(display = "unable to archive the current avatar")]
+    NotArchived,
 }
 
 #[derive(Serialize)]
 pub struct PictureUrl {
     pub url: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub signed_url: Option<String>,
+}
+
+/// The URL `file_name` is retrieved from, along with a signed one if
+/// configured. Only the unsigned one goes into the profile, it doesn't expire.
+fn picture_url(settings: &AvatarSettings, file_name: &ExternalFileName) -> PictureUrl {
+    let filename = file_name.filename();
+    let url = format!(
+        "{}{}{}",
+        settings.picture_api_url, settings.retrieve_by_id_path, &filename
+    );
+    let signed_url = settings
+        .url_signing
+        .as_ref()
+        .map(|signing| format!("{}?{}", url, sign(signing, &filename)));
+    PictureUrl { url, signed_url }
 }
 
 pub async fn delete_avatar(
     settings: &AvatarSettings,
-    saver: &Arc<impl Saver>,
+    storage: &Arc<impl Storage>,
     uuid: &str,
+    force: bool,
 ) -> Result<(), Error> {
+    let uuid_hashes = uuid_hashes(uuid, settings.uuid_hash_secret.as_deref());
+    if !force {
+        info!("moving avatar for {} to trash", uuid);
+        for (_, uuid_hash) in &uuid_hashes {
+            trash::trash(uuid_hash, &settings.s3_bucket, storage).await?;
+        }
+        return Ok(());
+    }
     info!("deleting avatar for {}", uuid);
-    let internal_file_names = vec![
-        InternalFileName::from_uuid_and_display(uuid, &Display::Public).to_string(),
-        InternalFileName::from_uuid_and_display(uuid, &Display::Authenticated).to_string(),
-        InternalFileName::from_uuid_and_display(uuid, &Display::Vouched).to_string(),
-        InternalFileName::from_uuid_and_display(uuid, &Display::Ndaed).to_string(),
-        InternalFileName::from_uuid_and_display(uuid, &Display::Staff).to_string(),
-        InternalFileName::from_uuid_and_display(uuid, &Display::Private).to_string(),
-    ];
-    delete_many(&internal_file_names, &settings.s3_bucket, saver).await?;
+    for (_, uuid_hash) in &uuid_hashes {
+        let internal_file_names = DISPLAY_LEVELS
+            .iter()
+            .map(|display| {
+                InternalFileName {
+                    uuid_hash: uuid_hash.clone(),
+                    display: display.clone(),
+                }
+                .to_string()
+            })
+            .collect::<Vec<_>>();
+        delete_many(&internal_file_names, &settings.s3_bucket, storage).await?;
+        trash::discard(uuid_hash, &settings.s3_bucket, storage).await?;
+    }
 
     Ok(())
 }
 
-pub async fn change_display_level(
+pub async fn restore_deleted_avatar(
+    settings: &AvatarSettings,
+    storage: &Arc<impl Storage>,
+    uuid: &str,
+) -> Result<PictureUrl, Error> {
+    info!("restoring deleted avatar for {}", uuid);
+    let mut restored = None;
+    for (version, uuid_hash) in uuid_hashes(uuid, settings.uuid_hash_secret.as_deref()) {
+        let displays = trash::restore(&uuid_hash, &settings.s3_bucket, storage).await?;
+        if let (None, Some(display)) = (&restored, displays.first()) {
+            // served under the hash it was trashed with
+            restored = Some(ExternalFileName::from_hash_and_display(
+                version, &uuid_hash, display,
+            ));
+        }
+    }
+    let file_name = restored.ok_or(SaveError::NothingToRestore)?;
+    Ok(picture_url(settings, &file_name))
+}
+
+pub async fn purge_deleted_avatars(
+    settings: &AvatarSettings,
+    storage: &Arc<impl Storage>,
+) -> Result<usize, Error> {
+    trash::purge_expired(
+        &settings.s3_bucket,
+        Duration::days(settings.trash_retention_days),
+        storage,
+    )
+    .await
+}
+
+pub async fn avatar_history(
     settings: &AvatarSettings,
     loader: &Arc<impl Loader>,
+    uuid: &str,
+) -> Result<Vec<HistoryEntry>, Error> {
+    let mut entries = vec![];
+    for (_, uuid_hash) in uuid_hashes(uuid, settings.uuid_hash_secret.as_deref()) {
+        entries.extend(load_history(&uuid_hash, &settings.s3_bucket, loader).await?);
+    }
+    entries.sort_by_key(|entry| Reverse(entry.ts));
+    Ok(entries)
+}
+
+pub async fn restore_avatar(
+    settings: &AvatarSettings,
+    saver: &Arc<impl Saver>,
+    loader: &Arc<impl Loader>,
+    uuid: &str,
+    restore: &Restore,
+) -> Result<PictureUrl, Error> {
+    info!("restoring version {} for {}", restore.ts, uuid);
+    let secret = settings.uuid_hash_secret.as_deref();
+    let bucket = &settings.s3_bucket;
+    let mut found = None;
+    for (_, uuid_hash) in uuid_hashes(uuid, secret) {
+        let entry = load_history(&uuid_hash, bucket, loader)
+            .await?
+            .into_iter()
+            .find(|entry| entry.ts == restore.ts);
+        if let Some(entry) = entry {
+            found = Some((uuid_hash, entry));
+            break;
+        }
+    }
+    let (uuid_hash, entry) = found.ok_or(HistoryError::UnknownVersion)?;
+    let display = restore.display.as_ref().unwrap_or(&entry.display);
+    let file_name = ExternalFileName::from_uuid_and_display(uuid, display, secret);
+    let result = picture_url(settings, &file_name);
+    // Load the version first, archiving the current avatar may expire it.
+    let avatars = load_version(&uuid_hash, entry.ts, bucket, loader).await?;
+    replace_avatar(
+        settings,
+        saver,
+        loader,
+        uuid,
+        avatars,
+        &file_name,
+        &restore.old_url,
+    )
+    .await?;
+    forget(&uuid_hash, entry.ts, bucket, saver, loader).await?;
+    Ok(result)
+}
+
+pub async fn change_display_level(
+    settings: &AvatarSettings,
     saver: &Arc<impl Saver>,
     uuid: &str,
     change_display: &ChangeDisplay,
 ) -> Result<PictureUrl, Error> {
     info!("changing display level for {}", uuid);
+    let secret = settings.uuid_hash_secret.as_deref();
     let old_file_name = ExternalFileName::from_uri(&change_display.old_url)?;
-    let file_name = ExternalFileName::from_uuid_and_display(uuid, &change_display.display);
-    let result = PictureUrl {
-        url: format!(
-            "{}{}{}",
-            settings.picture_api_url,
-            settings.retrieve_by_id_path,
-            &file_name.filename()
-        ),
-    };
-    if old_file_name.internal.uuid_hash != file_name.internal.uuid_hash {
+    let file_name = ExternalFileName::from_uuid_and_display(uuid, &change_display.display, secret);
+    let result = picture_url(settings, &file_name);
+    if !old_file_name.belongs_to(uuid, secret) {
         return Err(SaveError::UuidMismatch.into());
     }
     rename(
@@ -78,7 +212,6 @@ pub async fn change_display_level(
         &file_name.internal.to_string(),
         &settings.s3_bucket,
         saver,
-        loader,
     )
     .await?;
     Ok(result)
@@ -92,43 +225,125 @@ pub async fn check_resize_store_intermediate(
     save: Save,
 ) -> Result<PictureUrl, Error> {
     let buf = loader
-        .load(&save.intermediate, "tmp", &settings.s3_bucket)
-        .await?;
-    check_resize_store(settings, saver, uuid, buf, &save.display, &save.old_url).await
+        .load(&save.intermediate, TMP, &settings.s3_bucket)
+        .await
+        .map_err(|e| match is_not_found(&e) {
+            true => SaveError::MissingIntermediate.into(),
+            false => e,
+        })?;
+    check_resize_store(
+        settings,
+        saver,
+        loader,
+        uuid,
+        buf,
+        &save.display,
+        &save.old_url,
+    )
+    .await
+}
+
+/// Stores `avatars` under `file_name` and afterwards moves the avatar behind
+/// `old_url` into the history. The old avatar is only removed once the new set
+/// is complete and it made it into the history.
+async fn replace_avatar(
+    settings: &AvatarSettings,
+    saver: &Arc<impl Saver>,
+    loader: &Arc<impl Loader>,
+    uuid: &str,
+    avatars: Avatars,
+    file_name: &ExternalFileName,
+    old_url: &Option<String>,
+) -> Result<(), Error> {
+    let bucket = &settings.s3_bucket;
+    let name = file_name.internal.to_string();
+    let old_file_name = match old_url
+        .as_ref()
+        .map(|old_url| ExternalFileName::from_uri(old_url))
+    {
+        Some(Ok(old_file_name)) => Some(old_file_name),
+        Some(Err(e)) => {
+            warn!(
+                "{} for {}: {}",
+                e,
+                uuid,
+                old_url.as_deref().unwrap_or_default()
+            );
+            None
+        }
+        None => None,
+    };
+    let old_file_name = match old_file_name {
+        Some(old_file_name) => old_file_name,
+        None => return save(avatars, &name, bucket, saver).await,
+    };
+
+    let archived = if !old_file_name.belongs_to(uuid, settings.uuid_hash_secret.as_deref()) {
+        warn!("not archiving foreign avatar for {}", uuid);
+        true
+    } else {
+        match archive(
+            &old_file_name,
+            bucket,
+            settings.history_versions,
+            saver,
+            loader,
+        )
+        .await
+        {
+            Ok(()) => true,
+            Err(e) => {
+                warn!("unable to archive avatar for {}: {}", uuid, e);
+                false
+            }
+        }
+    };
+
+    let old_name = old_file_name.internal.to_string();
+    if old_name == name {
+        if !archived {
+            // overwriting it would lose the only copy of the old avatar
+            return Err(SaveError::NotArchived.into());
+        }
+        // The new set overwrites the old one, keep it around for the rollback.
+        // Without it a failed save could only delete the live avatar.
+        let previous = match load(&old_name, bucket, loader).await {
+            Ok(previous) => Some(previous),
+            Err(e) if is_not_found(&e) => None,
+            Err(e) => return Err(e),
+        };
+        return replace(avatars, previous, &name, bucket, saver).await;
+    }
+    save(avatars, &name, bucket, saver).await?;
+    if !archived {
+        warn!("keeping unarchived old avatar for {}", uuid);
+    } else if let Err(e) = delete(&old_name, bucket, saver).await {
+        warn!("unable to delete old avatar for {}: {}", uuid, e);
+    }
+    Ok(())
 }
 
 async fn check_resize_store(
     settings: &AvatarSettings,
     saver: Arc<impl Saver>,
+    loader: Arc<impl Loader>,
     uuid: &str,
     buf: Vec<u8>,
     display: &Display,
     old_url: &Option<String>,
 ) -> Result<PictureUrl, Error> {
     info!("uploading image for {}", uuid);
-    let file_name = ExternalFileName::from_uuid_and_display(uuid, display);
+    let file_name = ExternalFileName::from_uuid_and_display(
+        uuid,
+        display,
+        settings.uuid_hash_secret.as_deref(),
+    );
     let avatars = Avatars::new(buf)?;
-    let bucket = settings.s3_bucket.clone();
-    let result = PictureUrl {
-        url: format!(
-            "{}{}{}",
-            settings.picture_api_url,
-            settings.retrieve_by_id_path,
-            &file_name.filename()
-        ),
-    };
-    if let Some(old_url) = old_url {
-        let old_file_name = ExternalFileName::from_uri(old_url);
-        match old_file_name {
-            Ok(name) => {
-                delete(&name.internal.to_string(), &settings.s3_bucket, &saver).await?;
-            }
-            Err(e) => {
-                warn!("{} for {}: {}", e, uuid, old_url);
-            }
-        }
-    }
-    save(avatars, &file_name.internal.to_string(), &bucket, &saver).await?;
+    let result = picture_url(settings, &file_name);
+    replace_avatar(
+        settings, &saver, &loader, uuid, avatars, &file_name, old_url,
+    )
+    .await?;
     Ok(result)
 }
 
@@ -143,6 +358,11 @@ pub async fn store_intermediate(
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::send::operations::LARGE;
+    use crate::send::operations::SIZES;
+    use crate::send::operations::SMALL;
+    use crate::settings::UrlSigningSettings;
+    use crate::storage::memory::MemoryStorage;
     use failure::format_err;
     use futures::future::BoxFuture;
 
@@ -175,16 +395,33 @@ mod test {
         fn save_tmp(&self, _: &str, _: Vec<u8>) -> BoxFuture<'_, Result<String, Error>> {
             Box::pin(async { Ok(String::from("936DA01F9ABD4d9d80C702AF85C822A8")) })
         }
+        fn copy(
+            &self,
+            _: &str,
+            _: &str,
+            _: &str,
+            _: &str,
+            _: &str,
+        ) -> BoxFuture<'_, Result<(), Error>> {
+            let ret = match self.save {
+                true => Ok(()),
+                false => Err(format_err!("doom")),
+            };
+            Box::pin(async move { ret })
+        }
+    }
+
+    struct DummyLoader {}
+    impl Loader for DummyLoader {
+        fn load(&self, _: &str, _: &str, _: &str) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+            Box::pin(async { Err(format_err!("doom")) })
+        }
     }
 
     #[tokio::test]
     async fn test_check_resize_store_without_old() -> Result<(), Error> {
         let data = include_bytes!("../../tests/data/dino.png");
-        let settings = AvatarSettings {
-            s3_bucket: String::from("testing"),
-            retrieve_by_id_path: String::from("/api/v666"),
-            picture_api_url: String::from("https://localhost"),
-        };
+        let settings = AvatarSettings::for_testing();
         let saver = Arc::new(DummySaver {
             delete: true,
             save: true,
@@ -193,6 +430,7 @@ mod test {
         check_resize_store(
             &settings,
             saver,
+            Arc::new(DummyLoader {}),
             uuid,
             data.to_vec(),
             &Display::Private,
@@ -205,11 +443,7 @@ mod test {
     #[tokio::test]
     async fn test_check_resize_store_with_old() -> Result<(), Error> {
         let data = include_bytes!("../../tests/data/dino.png");
-        let settings = AvatarSettings {
-            s3_bucket: String::from("testing"),
-            retrieve_by_id_path: String::from("/api/v666"),
-            picture_api_url: String::from("https://localhost"),
-        };
+        let settings = AvatarSettings::for_testing();
         let saver = Arc::new(DummySaver {
             delete: true,
             save: true,
@@ -220,12 +454,129 @@ mod test {
         check_resize_store(
             &settings,
             saver,
+            Arc::new(DummyLoader {}),
+            uuid,
+            data.to_vec(),
+            &Display::Private,
+            &old_url,
+        )
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_old_avatar_is_kept_when_archiving_fails() -> Result<(), Error> {
+        let data = include_bytes!("../../tests/data/dino.png");
+        let settings = AvatarSettings::for_testing();
+        let store = Arc::new(MemoryStorage::default().fail_load_prefix("history"));
+        let uuid = "9e697947-2990-4182-b080-533c16af4799";
+        let old = ExternalFileName::from_uuid_and_display(uuid, &Display::Staff, None);
+        for size in SIZES.iter() {
+            store.insert(&old.internal.to_string(), size, "testing", vec![1]);
+        }
+        let old_url = Some(old.filename());
+
+        let res = check_resize_store(
+            &settings,
+            store.clone(),
+            store.clone(),
+            uuid,
+            data.to_vec(),
+            &Display::Staff,
+            &old_url,
+        )
+        .await;
+        assert!(matches!(
+            res.err().unwrap().downcast::<SaveError>(),
+            Ok(SaveError::NotArchived)
+        ));
+
+        check_resize_store(
+            &settings,
+            store.clone(),
+            store.clone(),
             uuid,
             data.to_vec(),
             &Display::Private,
             &old_url,
         )
         .await?;
+        assert!(Storage::exists(&*store, &old.internal.to_string(), SMALL, "testing").await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_replace_needs_the_previous_avatar() -> Result<(), Error> {
+        let data = include_bytes!("../../tests/data/dino.png");
+        let settings = AvatarSettings::for_testing();
+        let store = Arc::new(MemoryStorage::default().fail_load_prefix(SMALL));
+        let uuid = "9e697947-2990-4182-b080-533c16af4799";
+        let old = ExternalFileName::from_uuid_and_display(uuid, &Display::Staff, None);
+        for size in SIZES.iter() {
+            store.insert(&old.internal.to_string(), size, "testing", vec![1]);
+        }
+
+        let res = check_resize_store(
+            &settings,
+            store.clone(),
+            store.clone(),
+            uuid,
+            data.to_vec(),
+            &Display::Staff,
+            &Some(old.filename()),
+        )
+        .await;
+        assert!(res.is_err());
+        assert_eq!(
+            Storage::load(&*store, &old.internal.to_string(), LARGE, "testing").await?,
+            vec![1]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_legacy_avatars_are_found() -> Result<(), Error> {
+        let settings = AvatarSettings {
+            uuid_hash_secret: Some(String::from("secret")),
+            ..AvatarSettings::for_testing()
+        };
+        let store = Arc::new(MemoryStorage::default());
+        let uuid = "9e697947-2990-4182-b080-533c16af4799";
+        // stored before the secret was set
+        let old = ExternalFileName::from_uuid_and_display(uuid, &Display::Staff, None);
+        let name = old.internal.to_string();
+        for size in SIZES.iter() {
+            store.insert(&name, size, "testing", vec![1]);
+        }
+        archive(&old, "testing", 5, &store, &store).await?;
+        assert_eq!(avatar_history(&settings, &store, uuid).await?.len(), 1);
+
+        delete_avatar(&settings, &store, uuid, false).await?;
+        assert!(!Storage::exists(&*store, &name, SMALL, "testing").await?);
+        let url = restore_deleted_avatar(&settings, &store, uuid).await?.url;
+        assert!(Storage::exists(&*store, &name, SMALL, "testing").await?);
+        assert_eq!(ExternalFileName::from_uri(&url)?.internal, old.internal);
+
+        delete_avatar(&settings, &store, uuid, true).await?;
+        assert!(!Storage::exists(&*store, &name, SMALL, "testing").await?);
+        assert!(avatar_history(&settings, &store, uuid).await?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_only_the_handed_out_url_is_signed() -> Result<(), Error> {
+        let mut settings = AvatarSettings::for_testing();
+        let file_name = ExternalFileName::from_uuid_and_display("uuid", &Display::Staff, None);
+        assert_eq!(picture_url(&settings, &file_name).signed_url, None);
+
+        settings.url_signing = Some(UrlSigningSettings {
+            secret: String::from("secret"),
+            ttl_seconds: 60,
+        });
+        let PictureUrl { url, signed_url } = picture_url(&settings, &file_name);
+        assert!(!url.contains('?'));
+        let signed_url = signed_url.unwrap();
+        assert!(signed_url.starts_with(&format!("{}?expires=", url)));
         Ok(())
     }
 }
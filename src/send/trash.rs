@@ -0,0 +1,366 @@
+use crate::send::history;
+use crate::send::operations::SIZES;
+use crate::storage::is_not_found;
+use crate::storage::name::InternalFileName;
+use crate::storage::name::DISPLAY_LEVELS;
+use crate::storage::Storage;
+use chrono::Duration;
+use chrono::Utc;
+use cis_profile::schema::Display;
+use failure::Error;
+use futures::future;
+use log::info;
+use log::warn;
+use serde::Deserialize;
+use serde::Serialize;
+use std::sync::Arc;
+
+const TRASH: &str = "trash";
+/// One entry per uuid hash, so replicas never rewrite each other's entries.
+const ENTRIES: &str = "trash/entries";
+/// The single index all entries used to live in.
+const LEGACY_INDEX: &str = "index.json";
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct TrashEntry {
+    pub uuid_hash: String,
+    pub deleted_at: i64,
+    pub displays: Vec<Display>,
+}
+
+fn live_prefix(size: &str) -> String {
+    size.to_owned()
+}
+
+pub fn trash_prefix(size: &str) -> String {
+    format!("{}/{}", TRASH, size)
+}
+
+fn entry_name(uuid_hash: &str) -> String {
+    format!("{}.json", uuid_hash)
+}
+
+/// Loads the trash entry of `uuid_hash`, `None` if it is not trashed.
+pub async fn load_entry(
+    uuid_hash: &str,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<Option<TrashEntry>, Error> {
+    match storage.load(&entry_name(uuid_hash), ENTRIES, bucket).await {
+        Ok(buf) => Ok(Some(serde_json::from_slice(&buf)?)),
+        Err(e) if is_not_found(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+async fn save_entry(
+    entry: &TrashEntry,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<(), Error> {
+    let buf = serde_json::to_vec(entry)?;
+    storage
+        .save(&entry_name(&entry.uuid_hash), ENTRIES, bucket, buf)
+        .await
+}
+
+async fn delete_entry(
+    uuid_hash: &str,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<(), Error> {
+    storage
+        .delete(&entry_name(uuid_hash), ENTRIES, bucket)
+        .await
+}
+
+/// Splits the legacy index into one entry per uuid hash. Entries which
+/// already exist win, so running this concurrently is harmless.
+async fn migrate_index(bucket: &str, storage: &Arc<impl Storage>) -> Result<(), Error> {
+    let entries: Vec<TrashEntry> = match storage.load(LEGACY_INDEX, TRASH, bucket).await {
+        Ok(buf) => serde_json::from_slice(&buf)?,
+        Err(e) if is_not_found(&e) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    for entry in &entries {
+        if load_entry(&entry.uuid_hash, bucket, storage)
+            .await?
+            .is_none()
+        {
+            save_entry(entry, bucket, storage).await?;
+        }
+    }
+    storage.delete(LEGACY_INDEX, TRASH, bucket).await?;
+    info!("migrated {} trash index entries", entries.len());
+    Ok(())
+}
+
+/// Loads all trash entries. Entries removed while listing are skipped.
+async fn load_trash(bucket: &str, storage: &Arc<impl Storage>) -> Result<Vec<TrashEntry>, Error> {
+    let mut entries = vec![];
+    for name in storage.list(ENTRIES, bucket).await? {
+        let uuid_hash = match name.strip_suffix(".json") {
+            Some(uuid_hash) => uuid_hash,
+            None => continue,
+        };
+        if let Some(entry) = load_entry(uuid_hash, bucket, storage).await? {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Moves all sizes of `name` which exist. Returns whether anything was moved.
+/// Sizes which were never generated are skipped, any other failure is passed
+/// on so an avatar is not reported as moved while it is still in place.
+async fn move_sizes(
+    name: &str,
+    from: impl Fn(&str) -> String,
+    to: impl Fn(&str) -> String,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<bool, Error> {
+    let moved = future::try_join_all(SIZES.iter().map(|size| {
+        let (from, to) = (from(size), to(size));
+        async move {
+            match storage.exists(name, &from, bucket).await {
+                Ok(true) => (),
+                Ok(false) => return Ok(false),
+                Err(e) => warn!("unable to check {}/{}: {}", from, name, e),
+            }
+            match storage.rename(name, &from, name, &to, bucket).await {
+                Ok(_) => Ok(true),
+                Err(e) if is_not_found(&e) => Ok(false),
+                Err(e) => Err(e),
+            }
+        }
+    }))
+    .await?;
+    Ok(moved.into_iter().any(|moved| moved))
+}
+
+fn internal_name(uuid_hash: &str, display: &Display) -> String {
+    InternalFileName {
+        uuid_hash: uuid_hash.to_owned(),
+        display: display.clone(),
+    }
+    .to_string()
+}
+
+/// Moves all display level variants stored under `uuid_hash` into the trash.
+/// Nothing is recorded for a hash without any avatar.
+pub async fn trash(
+    uuid_hash: &str,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<(), Error> {
+    let mut displays = vec![];
+    for display in DISPLAY_LEVELS.iter() {
+        let name = internal_name(uuid_hash, display);
+        if move_sizes(&name, live_prefix, trash_prefix, bucket, storage).await? {
+            displays.push(display.clone());
+        }
+    }
+    migrate_index(bucket, storage).await?;
+    // Trashing twice must not reset the retention window.
+    let deleted_at = match load_entry(uuid_hash, bucket, storage).await? {
+        Some(entry) => entry.deleted_at,
+        None if displays.is_empty() => return Ok(()),
+        None => Utc::now().timestamp(),
+    };
+    let entry = TrashEntry {
+        uuid_hash: uuid_hash.to_owned(),
+        deleted_at,
+        displays,
+    };
+    save_entry(&entry, bucket, storage).await?;
+    info!("moved avatar {} to trash", uuid_hash);
+    Ok(())
+}
+
+/// Moves the avatar trashed under `uuid_hash` back. Returns the restored
+/// display levels.
+pub async fn restore(
+    uuid_hash: &str,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<Vec<Display>, Error> {
+    let mut displays = vec![];
+    for display in DISPLAY_LEVELS.iter() {
+        let name = internal_name(uuid_hash, display);
+        if move_sizes(&name, trash_prefix, live_prefix, bucket, storage).await? {
+            displays.push(display.clone());
+        }
+    }
+    migrate_index(bucket, storage).await?;
+    delete_entry(uuid_hash, bucket, storage).await?;
+    if !displays.is_empty() {
+        info!("restored avatar {} from trash", uuid_hash);
+    }
+    Ok(displays)
+}
+
+async fn discard_entry(
+    uuid_hash: &str,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<(), Error> {
+    let names = DISPLAY_LEVELS
+        .iter()
+        .map(|display| internal_name(uuid_hash, display))
+        .collect::<Vec<_>>();
+    future::try_join_all(
+        SIZES
+            .iter()
+            .map(|size| storage.delete_many(&names, &trash_prefix(size), bucket)),
+    )
+    .await?;
+    history::purge(uuid_hash, bucket, storage).await
+}
+
+/// Hard deletes the trashed avatar of `uuid_hash` regardless of the retention.
+pub async fn discard(
+    uuid_hash: &str,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<(), Error> {
+    discard_entry(uuid_hash, bucket, storage).await?;
+    migrate_index(bucket, storage).await?;
+    delete_entry(uuid_hash, bucket, storage).await
+}
+
+/// Hard deletes all trashed avatars older than `retention`. Returns the number
+/// of purged avatars.
+pub async fn purge_expired(
+    bucket: &str,
+    retention: Duration,
+    storage: &Arc<impl Storage>,
+) -> Result<usize, Error> {
+    migrate_index(bucket, storage).await?;
+    let cutoff = (Utc::now() - retention).timestamp();
+    let expired = load_trash(bucket, storage)
+        .await?
+        .into_iter()
+        .filter(|entry| entry.deleted_at <= cutoff)
+        .collect::<Vec<_>>();
+    for entry in &expired {
+        discard_entry(&entry.uuid_hash, bucket, storage).await?;
+        delete_entry(&entry.uuid_hash, bucket, storage).await?;
+        info!("purged trashed avatar {}", entry.uuid_hash);
+    }
+    Ok(expired.len())
+}
+
+/// Points the trash entry of `from` to the uuid hash `to`.
+pub async fn rehash_entry(
+    from: &str,
+    to: &str,
+    bucket: &str,
+    storage: &Arc<impl Storage>,
+) -> Result<(), Error> {
+    migrate_index(bucket, storage).await?;
+    let entry = match load_entry(from, bucket, storage).await? {
+        Some(entry) => entry,
+        None => return Ok(()),
+    };
+    let entry = TrashEntry {
+        uuid_hash: to.to_owned(),
+        ..entry
+    };
+    save_entry(&entry, bucket, storage).await?;
+    delete_entry(from, bucket, storage).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::send::operations::SMALL;
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::name::sha256_uuid_hash;
+
+    #[tokio::test]
+    async fn test_trash_restore_and_purge() -> Result<(), Error> {
+        let uuid_hash = sha256_uuid_hash("9e697947-2990-4182-b080-533c16af4799");
+        let store = Arc::new(MemoryStorage::default());
+        let name = internal_name(&uuid_hash, &Display::Staff);
+        for size in SIZES.iter() {
+            store.save(&name, size, "testing", vec![1]).await?;
+        }
+
+        trash(&uuid_hash, "testing", &store).await?;
+        assert!(store.load(&name, SMALL, "testing").await.is_err());
+        assert_eq!(
+            restore(&uuid_hash, "testing", &store).await?,
+            vec![Display::Staff]
+        );
+        assert!(store.load(&name, SMALL, "testing").await.is_ok());
+
+        trash(&uuid_hash, "testing", &store).await?;
+        assert_eq!(
+            purge_expired("testing", Duration::days(1), &store).await?,
+            0
+        );
+        assert_eq!(purge_expired("testing", Duration::zero(), &store).await?, 1);
+        assert!(store
+            .load(&name, &trash_prefix(SMALL), "testing")
+            .await
+            .is_err());
+        assert!(restore(&uuid_hash, "testing", &store).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_legacy_index_is_migrated() -> Result<(), Error> {
+        let store = Arc::new(MemoryStorage::default());
+        let entries = vec![TrashEntry {
+            uuid_hash: String::from("abc"),
+            deleted_at: 1,
+            displays: vec![Display::Staff],
+        }];
+        store.insert(
+            LEGACY_INDEX,
+            TRASH,
+            "testing",
+            serde_json::to_vec(&entries)?,
+        );
+        assert_eq!(purge_expired("testing", Duration::zero(), &store).await?, 1);
+        assert!(!store.exists(LEGACY_INDEX, TRASH, "testing").await?);
+        assert!(load_trash("testing", &store).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_entry_is_not_reset() -> Result<(), Error> {
+        let uuid_hash = sha256_uuid_hash("9e697947-2990-4182-b080-533c16af4799");
+        let store = Arc::new(MemoryStorage::default().fail_load_prefix(ENTRIES));
+        let entry = TrashEntry {
+            uuid_hash: uuid_hash.clone(),
+            deleted_at: 1,
+            displays: vec![],
+        };
+        store.insert(
+            &entry_name(&entry.uuid_hash),
+            ENTRIES,
+            "testing",
+            serde_json::to_vec(&entry)?,
+        );
+        assert!(trash(&uuid_hash, "testing", &store).await.is_err());
+        assert!(purge_expired("testing", Duration::zero(), &store)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_failed_move_is_reported() -> Result<(), Error> {
+        let uuid_hash = sha256_uuid_hash("9e697947-2990-4182-b080-533c16af4799");
+        let store = Arc::new(MemoryStorage::default().fail_prefix(&trash_prefix(SMALL)));
+        let name = internal_name(&uuid_hash, &Display::Staff);
+        for size in SIZES.iter() {
+            store.insert(&name, size, "testing", vec![1]);
+        }
+        assert!(trash(&uuid_hash, "testing", &store).await.is_err());
+        assert!(store.exists(&name, SMALL, "testing").await?);
+        Ok(())
+    }
+}
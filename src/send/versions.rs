@@ -0,0 +1,107 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
+use crate::send::operations::SIZES;
+use crate::settings::AvatarSettings;
+use crate::storage::loader::Loader;
+use crate::storage::name::InternalFileName;
+use crate::storage::Storage;
+use crate::storage::Version;
+use cis_profile::schema::Display;
+use failure::Error;
+use log::info;
+use std::sync::Arc;
+
+#[derive(Debug, Fail, PartialEq)]
+pub enum VersionError {
+    #[fail(display = "unknown picture size")]
+    UnknownSize,
+}
+
+/// Name of the current derivative in `size`.
+fn derivative(
+    settings: &AvatarSettings,
+    uuid: &str,
+    display: &Display,
+    size: &str,
+) -> Result<String, Error> {
+    if !SIZES.contains(&size) {
+        return Err(VersionError::UnknownSize.into());
+    }
+    let secret = settings.uuid_hash_secret.as_deref();
+    Ok(InternalFileName::from_uuid_and_display(uuid, display, secret).to_string())
+}
+
+/// Lists the versions the bucket keeps of a derivative.
+pub async fn list_versions(
+    settings: &AvatarSettings,
+    storage: &Arc<impl Storage>,
+    uuid: &str,
+    display: &Display,
+    size: &str,
+) -> Result<Vec<Version>, Error> {
+    let name = derivative(settings, uuid, display, size)?;
+    storage.versions(&name, size, &settings.s3_bucket).await
+}
+
+pub async fn load_version(
+    settings: &AvatarSettings,
+    loader: &Arc<impl Loader>,
+    uuid: &str,
+    display: &Display,
+    size: &str,
+    version_id: &str,
+) -> Result<Vec<u8>, Error> {
+    let name = derivative(settings, uuid, display, size)?;
+    loader
+        .load_version(&name, size, &settings.s3_bucket, Some(version_id))
+        .await
+}
+
+/// Makes a previous version the current one again. The replaced one stays
+/// available as a version itself.
+pub async fn restore_version(
+    settings: &AvatarSettings,
+    storage: &Arc<impl Storage>,
+    uuid: &str,
+    display: &Display,
+    size: &str,
+    version_id: &str,
+) -> Result<(), Error> {
+    info!("restoring {} version {} for {}", size, version_id, uuid);
+    let name = derivative(settings, uuid, display, size)?;
+    let bucket = &settings.s3_bucket;
+    let buf = Storage::load_version(&**storage, &name, size, bucket, Some(version_id)).await?;
+    Storage::save(&**storage, &name, size, bucket, buf).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+    use crate::storage::StorageError;
+
+    #[tokio::test]
+    async fn test_unknown_size_is_rejected() {
+        let storage = Arc::new(MemoryStorage::default());
+        let settings = AvatarSettings::for_testing();
+        let res = list_versions(&settings, &storage, "uuid", &Display::Public, "../raw").await;
+        assert_eq!(
+            res.err().unwrap().downcast::<VersionError>().unwrap(),
+            VersionError::UnknownSize
+        );
+    }
+
+    #[tokio::test]
+    async fn test_unversioned_backend() {
+        let storage = Arc::new(MemoryStorage::default());
+        let settings = AvatarSettings::for_testing();
+        let res = restore_version(&settings, &storage, "uuid", &Display::Public, "40", "v1").await;
+        assert!(matches!(
+            res.err().unwrap().downcast::<StorageError>(),
+            Ok(StorageError::VersionsUnsupported)
+        ));
+    }
+}
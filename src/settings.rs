@@ -1,6 +1,7 @@
 use cis_client::settings::CisSettings;
 use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::env;
 
 #[derive(Debug, Deserialize, Clone)]
@@ -8,6 +9,261 @@ pub struct AvatarSettings {
     pub s3_bucket: String,
     pub retrieve_by_id_path: String,
     pub picture_api_url: String,
+    /// Number of previous avatar versions kept under the history prefix.
+    #[serde(default = "default_history_versions")]
+    pub history_versions: usize,
+    /// Days a deleted avatar stays in the trash before it gets purged.
+    #[serde(default = "default_trash_retention_days")]
+    pub trash_retention_days: i64,
+    /// Minutes an uploaded intermediate picture is kept before it expires.
+    #[serde(default = "default_tmp_ttl_minutes")]
+    pub tmp_ttl_minutes: i64,
+    /// Largest accepted upload of an intermediate picture.
+    #[serde(default = "default_max_upload_bytes")]
+    pub max_upload_bytes: usize,
+    /// `Cache-Control` of stored pictures.
+    #[serde(default = "default_s3_cache_control")]
+    pub s3_cache_control: String,
+    /// Server side encryption of everything put into the bucket.
+    #[serde(default)]
+    pub s3_encryption: ServerSideEncryption,
+    /// An S3 compatible endpoint (MinIO, LocalStack) to use instead of AWS.
+    #[serde(default)]
+    pub s3_endpoint: Option<S3EndpointSettings>,
+    /// Signs picture URLs and lets them expire.
+    #[serde(default)]
+    pub url_signing: Option<UrlSigningSettings>,
+    /// Key of the uuid hashes in new picture names, plain SHA-256 without.
+    #[serde(default)]
+    pub uuid_hash_secret: Option<String>,
+}
+
+#[cfg(test)]
+impl AvatarSettings {
+    /// Settings of a `testing` bucket with the defaults of everything else.
+    pub fn for_testing() -> Self {
+        serde_json::from_value(serde_json::json!({
+            "s3_bucket": "testing",
+            "retrieve_by_id_path": "/avatar/get/id/",
+            "picture_api_url": "https://localhost",
+        }))
+        .unwrap()
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct UrlSigningSettings {
+    /// HMAC-SHA256 key of the signatures.
+    pub secret: String,
+    /// Seconds a signed URL stays valid.
+    #[serde(default = "default_url_ttl_seconds")]
+    pub ttl_seconds: i64,
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+#[serde(tag = "mode", rename_all = "lowercase")]
+pub enum ServerSideEncryption {
+    /// Leaves it to the bucket default.
+    #[default]
+    None,
+    /// SSE-S3 with S3 managed keys.
+    S3,
+    /// SSE-KMS, with the AWS managed key unless `key_id` is set.
+    Kms {
+        #[serde(default)]
+        key_id: Option<String>,
+    },
+}
+
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct S3EndpointSettings {
+    pub url: String,
+    #[serde(default = "default_s3_region")]
+    pub region: String,
+    /// Virtual-hosted style addressing is not supported by rusoto, this has to
+    /// stay `true`.
+    #[serde(default = "default_s3_path_style")]
+    pub path_style: bool,
+    /// Static credentials, the default AWS credential chain is used if unset.
+    #[serde(default)]
+    pub access_key_id: Option<String>,
+    #[serde(default)]
+    pub secret_access_key: Option<String>,
+}
+
+fn default_history_versions() -> usize {
+    5
+}
+
+fn default_trash_retention_days() -> i64 {
+    30
+}
+
+fn default_tmp_ttl_minutes() -> i64 {
+    60
+}
+
+fn default_max_upload_bytes() -> usize {
+    10 * 1024 * 1024
+}
+
+fn default_s3_cache_control() -> String {
+    String::from("max-age=3600")
+}
+
+fn default_url_ttl_seconds() -> i64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_s3_region() -> String {
+    String::from("us-east-1")
+}
+
+fn default_s3_path_style() -> bool {
+    true
+}
+
+/// Where pictures are stored, selected by `backend`.
+#[derive(Debug, Deserialize, Clone, PartialEq, Default)]
+#[serde(tag = "backend", rename_all = "lowercase")]
+pub enum StorageSettings {
+    #[default]
+    S3,
+    Filesystem {
+        path: String,
+    },
+    /// Nothing gets persisted, for local development only.
+    Memory {
+        #[serde(default)]
+        fail_nth_save: Option<usize>,
+        #[serde(default)]
+        fail_prefix: Option<String>,
+    },
+    /// Writes to both stores, reads from `secondary` when `primary` fails.
+    Mirror {
+        primary: Box<StorageSettings>,
+        secondary: Box<StorageSettings>,
+        #[serde(default)]
+        on_mismatch: MismatchPolicy,
+    },
+}
+
+/// What to do when a write reached the primary but not the secondary store.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MismatchPolicy {
+    /// Fail the write.
+    #[default]
+    Error,
+    /// Succeed and bring the secondary store up to date later.
+    Queue,
+}
+
+/// Client side encryption of pictures above `Display::Public`.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct EncryptionSettings {
+    /// Id of the key new objects are encrypted with.
+    pub active_key_id: String,
+    /// Base64 encoded 256 bit data keys by id. Rotated out keys stay until
+    /// nothing encrypted with them is left.
+    pub keys: HashMap<String, String>,
+    /// Serve unencrypted objects under names which get encrypted, while
+    /// pictures stored before enabling encryption are still around.
+    #[serde(default)]
+    pub allow_plaintext: bool,
+}
+
+/// Retries of failed storage calls with exponential backoff and full jitter.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct RetrySettings {
+    /// Total number of tries, `1` disables retries.
+    #[serde(default = "default_retry_attempts")]
+    pub attempts: u32,
+    /// Upper bound of the first backoff, doubled on every further retry.
+    #[serde(default = "default_retry_base_delay_ms")]
+    pub base_delay_ms: u64,
+    #[serde(default = "default_retry_max_delay_ms")]
+    pub max_delay_ms: u64,
+    /// Timeout of a single try.
+    #[serde(default = "default_retry_timeout_ms")]
+    pub timeout_ms: u64,
+}
+
+impl Default for RetrySettings {
+    fn default() -> Self {
+        RetrySettings {
+            attempts: default_retry_attempts(),
+            base_delay_ms: default_retry_base_delay_ms(),
+            max_delay_ms: default_retry_max_delay_ms(),
+            timeout_ms: default_retry_timeout_ms(),
+        }
+    }
+}
+
+fn default_retry_attempts() -> u32 {
+    3
+}
+
+fn default_retry_base_delay_ms() -> u64 {
+    100
+}
+
+fn default_retry_max_delay_ms() -> u64 {
+    2000
+}
+
+fn default_retry_timeout_ms() -> u64 {
+    10_000
+}
+
+/// Read-through cache of loaded pictures, `0` bytes disable a tier.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct CacheSettings {
+    #[serde(default = "default_cache_memory_max_bytes")]
+    pub memory_max_bytes: u64,
+    /// Directory of the disk tier, only used by this service.
+    #[serde(default)]
+    pub disk_path: Option<String>,
+    #[serde(default = "default_cache_disk_max_bytes")]
+    pub disk_max_bytes: u64,
+}
+
+impl Default for CacheSettings {
+    fn default() -> Self {
+        CacheSettings {
+            memory_max_bytes: default_cache_memory_max_bytes(),
+            disk_path: None,
+            disk_max_bytes: default_cache_disk_max_bytes(),
+        }
+    }
+}
+
+fn default_cache_memory_max_bytes() -> u64 {
+    64 * 1024 * 1024
+}
+
+fn default_cache_disk_max_bytes() -> u64 {
+    1024 * 1024 * 1024
+}
+
+/// Content addressed storage of derivatives and the collection of blobs no
+/// longer pointed to.
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+pub struct DedupSettings {
+    #[serde(default = "default_dedup_gc_interval_minutes")]
+    pub gc_interval_minutes: u64,
+    /// Unreferenced blobs younger than this are kept, they might be about to
+    /// be pointed to.
+    #[serde(default = "default_dedup_gc_grace_minutes")]
+    pub gc_grace_minutes: i64,
+}
+
+fn default_dedup_gc_interval_minutes() -> u64 {
+    60
+}
+
+fn default_dedup_gc_grace_minutes() -> i64 {
+    60
 }
 
 #[derive(Debug, Deserialize)]
@@ -15,6 +271,16 @@ pub struct Settings {
     pub auth: String,
     pub cis: CisSettings,
     pub avatar: AvatarSettings,
+    #[serde(default)]
+    pub storage: StorageSettings,
+    #[serde(default)]
+    pub retry: RetrySettings,
+    #[serde(default)]
+    pub cache: CacheSettings,
+    #[serde(default)]
+    pub encryption: Option<EncryptionSettings>,
+    #[serde(default)]
+    pub dedup: Option<DedupSettings>,
 }
 
 impl Settings {
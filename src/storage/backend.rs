@@ -0,0 +1,271 @@
+use super::filesystem::FilesystemStorage;
+use super::memory::MemoryStorage;
+use super::mirror::MirroredStorage;
+use super::s3::S3Storage;
+use super::Hashed;
+use super::Stat;
+use super::Storage;
+use super::Streamed;
+use super::Version;
+use crate::settings::AvatarSettings;
+use crate::settings::StorageSettings;
+use failure::Error;
+use futures::future::BoxFuture;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// The storage backend chosen in the settings.
+pub enum Backend {
+    S3(S3Storage),
+    Filesystem(FilesystemStorage),
+    Memory(MemoryStorage),
+    Mirror(Arc<MirroredStorage<Backend, Backend>>),
+}
+
+macro_rules! dispatch {
+    ($self:ident, $storage:ident => $call:expr) => {
+        match $self {
+            Backend::S3($storage) => $call,
+            Backend::Filesystem($storage) => $call,
+            Backend::Memory($storage) => $call,
+            Backend::Mirror($storage) => $call,
+        }
+    };
+}
+
+impl Backend {
+    pub fn from_settings(
+        settings: &StorageSettings,
+        avatar_settings: &AvatarSettings,
+    ) -> Result<Self, Error> {
+        Ok(match settings {
+            StorageSettings::S3 => Backend::S3(S3Storage::from_settings(avatar_settings)?),
+            StorageSettings::Filesystem { path } => Backend::Filesystem(FilesystemStorage {
+                path: Arc::new(PathBuf::from(path)),
+            }),
+            StorageSettings::Memory {
+                fail_nth_save,
+                fail_prefix,
+            } => {
+                let mut storage = MemoryStorage::default();
+                if let Some(n) = fail_nth_save {
+                    storage = storage.fail_nth_save(*n);
+                }
+                if let Some(prefix) = fail_prefix {
+                    storage = storage.fail_prefix(prefix);
+                }
+                Backend::Memory(storage)
+            }
+            StorageSettings::Mirror {
+                primary,
+                secondary,
+                on_mismatch,
+            } => Backend::Mirror(Arc::new(MirroredStorage::new(
+                Backend::from_settings(primary, avatar_settings)?,
+                Backend::from_settings(secondary, avatar_settings)?,
+                *on_mismatch,
+            ))),
+        })
+    }
+
+    /// Moves filesystem backends to the sharded layout. Returns the number of
+    /// moved files.
+    pub fn migrate_layout<'a>(&'a self, bucket: &'a str) -> BoxFuture<'a, Result<usize, Error>> {
+        Box::pin(async move {
+            match self {
+                Backend::Filesystem(storage) => storage.migrate_flat(bucket).await,
+                Backend::S3(_) | Backend::Memory(_) => Ok(0),
+                Backend::Mirror(mirror) => Ok(mirror.primary().migrate_layout(bucket).await?
+                    + mirror.secondary().migrate_layout(bucket).await?),
+            }
+        })
+    }
+
+    /// Whether tmp files expire without sweeping.
+    pub fn expires_tmp(&self) -> bool {
+        match self {
+            // S3 expires tmp files with a lifecycle rule
+            Backend::S3(_) => true,
+            Backend::Filesystem(_) | Backend::Memory(_) => false,
+            Backend::Mirror(mirror) => {
+                mirror.primary().expires_tmp() && mirror.secondary().expires_tmp()
+            }
+        }
+    }
+}
+
+impl Storage for Backend {
+    fn load(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        dispatch!(self, s => s.load(name, prefix, bucket))
+    }
+    fn load_hashed(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Hashed, Error>> {
+        dispatch!(self, s => s.load_hashed(name, prefix, bucket))
+    }
+    fn load_stream(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Streamed, Error>> {
+        dispatch!(self, s => s.load_stream(name, prefix, bucket))
+    }
+    fn load_version(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        version_id: Option<&str>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        dispatch!(self, s => s.load_version(name, prefix, bucket, version_id))
+    }
+    fn save(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        buf: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        dispatch!(self, s => s.save(name, prefix, bucket, buf))
+    }
+    fn delete(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<(), Error>> {
+        dispatch!(self, s => s.delete(name, prefix, bucket))
+    }
+    fn delete_many(
+        &self,
+        names: &[String],
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        dispatch!(self, s => s.delete_many(names, prefix, bucket))
+    }
+    fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>> {
+        dispatch!(self, s => s.save_tmp(bucket, buf))
+    }
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        dispatch!(self, s => s.copy(name, prefix, new_name, new_prefix, bucket))
+    }
+    fn rename(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        dispatch!(self, s => s.rename(name, prefix, new_name, new_prefix, bucket))
+    }
+    fn exists(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<bool, Error>> {
+        dispatch!(self, s => s.exists(name, prefix, bucket))
+    }
+    fn stat(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Stat, Error>> {
+        dispatch!(self, s => s.stat(name, prefix, bucket))
+    }
+    fn list(&self, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Vec<String>, Error>> {
+        dispatch!(self, s => s.list(prefix, bucket))
+    }
+    fn versions(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<Version>, Error>> {
+        dispatch!(self, s => s.versions(name, prefix, bucket))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn avatar_settings(s3_endpoint: serde_json::Value) -> Result<AvatarSettings, Error> {
+        Ok(serde_json::from_value(json!({
+            "s3_bucket": "testing",
+            "retrieve_by_id_path": "/avatar/get/id/",
+            "picture_api_url": "http://localhost",
+            "s3_endpoint": s3_endpoint,
+        }))?)
+    }
+
+    #[tokio::test]
+    async fn test_backend_from_settings() -> Result<(), Error> {
+        let avatar_settings = avatar_settings(json!(null))?;
+        let settings: StorageSettings =
+            serde_json::from_str(r#"{ "backend": "filesystem", "path": "./files" }"#)?;
+        assert!(matches!(
+            Backend::from_settings(&settings, &avatar_settings)?,
+            Backend::Filesystem(_)
+        ));
+        let settings: StorageSettings =
+            serde_json::from_str(r#"{ "backend": "memory", "fail_prefix": "tmp" }"#)?;
+        assert_eq!(
+            settings,
+            StorageSettings::Memory {
+                fail_nth_save: None,
+                fail_prefix: Some(String::from("tmp")),
+            }
+        );
+        assert!(matches!(
+            Backend::from_settings(&settings, &avatar_settings)?,
+            Backend::Memory(_)
+        ));
+        let settings: StorageSettings = serde_json::from_str(
+            r#"{
+                "backend": "mirror",
+                "primary": { "backend": "s3" },
+                "secondary": { "backend": "filesystem", "path": "./files" },
+                "on_mismatch": "queue"
+            }"#,
+        )?;
+        let backend = Backend::from_settings(&settings, &avatar_settings)?;
+        assert!(matches!(backend, Backend::Mirror(_)));
+        assert!(!backend.expires_tmp());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_custom_s3_endpoint() -> Result<(), Error> {
+        let minio = avatar_settings(json!({
+            "url": "http://localhost:9000",
+            "access_key_id": "minio",
+            "secret_access_key": "minio123",
+        }))?;
+        let endpoint = minio.s3_endpoint.as_ref().unwrap();
+        assert_eq!(endpoint.region, "us-east-1");
+        assert!(endpoint.path_style);
+        assert!(matches!(
+            Backend::from_settings(&StorageSettings::S3, &minio)?,
+            Backend::S3(_)
+        ));
+
+        let virtual_hosted = avatar_settings(json!({
+            "url": "http://localhost:9000",
+            "path_style": false,
+        }))?;
+        assert!(Backend::from_settings(&StorageSettings::S3, &virtual_hosted).is_err());
+
+        let key_only = avatar_settings(json!({
+            "url": "http://localhost:9000",
+            "access_key_id": "minio",
+        }))?;
+        assert!(Backend::from_settings(&StorageSettings::S3, &key_only).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,598 @@
+use super::sha256_hex;
+use super::Hashed;
+use super::Stat;
+use super::Storage;
+use super::Streamed;
+use super::Version;
+use crate::settings::CacheSettings;
+use async_std::fs;
+use failure::Error;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use log::warn;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+const DISK_SUFFIX: &str = "cache";
+
+struct Entry<V> {
+    tick: u64,
+    size: u64,
+    value: V,
+}
+
+/// Least recently used entries are evicted once `max_bytes` is exceeded.
+struct Lru<V> {
+    entries: HashMap<String, Entry<V>>,
+    order: BTreeMap<u64, String>,
+    tick: u64,
+    bytes: u64,
+    max_bytes: u64,
+}
+
+impl<V> Lru<V> {
+    fn new(max_bytes: u64) -> Self {
+        Lru {
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+            bytes: 0,
+            max_bytes,
+        }
+    }
+
+    fn get(&mut self, key: &str) -> Option<&V> {
+        let entry = self.entries.get_mut(key)?;
+        self.order.remove(&entry.tick);
+        self.tick += 1;
+        entry.tick = self.tick;
+        self.order.insert(self.tick, key.to_owned());
+        Some(&entry.value)
+    }
+
+    /// Returns the evicted entries. Values larger than `max_bytes` are not
+    /// inserted at all.
+    fn insert(&mut self, key: String, value: V, size: u64) -> Vec<(String, V)> {
+        let mut evicted: Vec<_> = self
+            .remove(&key)
+            .into_iter()
+            .map(|v| (key.clone(), v))
+            .collect();
+        if size > self.max_bytes {
+            return evicted;
+        }
+        while self.bytes + size > self.max_bytes {
+            let oldest = match self.order.keys().next() {
+                Some(tick) => self.order[tick].clone(),
+                None => break,
+            };
+            if let Some(value) = self.remove(&oldest) {
+                evicted.push((oldest, value));
+            }
+        }
+        self.tick += 1;
+        self.order.insert(self.tick, key.clone());
+        self.bytes += size;
+        self.entries.insert(
+            key,
+            Entry {
+                tick: self.tick,
+                size,
+                value,
+            },
+        );
+        evicted
+    }
+
+    fn remove(&mut self, key: &str) -> Option<V> {
+        let entry = self.entries.remove(key)?;
+        self.order.remove(&entry.tick);
+        self.bytes -= entry.size;
+        Some(entry.value)
+    }
+}
+
+/// Files named after the hash of their cache key, indexed in memory.
+struct DiskCache {
+    path: PathBuf,
+    index: Mutex<Lru<()>>,
+}
+
+impl DiskCache {
+    /// Entries of a previous run might be stale, they are removed.
+    fn new(path: &str, max_bytes: u64) -> Result<Self, Error> {
+        let path = PathBuf::from(path);
+        std::fs::create_dir_all(&path)?;
+        for entry in std::fs::read_dir(&path)? {
+            let entry = entry?.path();
+            if entry
+                .extension()
+                .map(|e| e == DISK_SUFFIX)
+                .unwrap_or_default()
+            {
+                std::fs::remove_file(entry)?;
+            }
+        }
+        Ok(DiskCache {
+            path,
+            index: Mutex::new(Lru::new(max_bytes)),
+        })
+    }
+
+    fn file(&self, key: &str) -> PathBuf {
+        self.path
+            .join(format!("{}.{}", sha256_hex(key.as_bytes()), DISK_SUFFIX))
+    }
+
+    async fn get(&self, key: &str) -> Option<Vec<u8>> {
+        self.index.lock().unwrap().get(key)?;
+        match fs::read(self.file(key)).await {
+            Ok(buf) => Some(buf),
+            Err(e) => {
+                warn!("unable to read cached {}: {}", key, e);
+                self.index.lock().unwrap().remove(key);
+                None
+            }
+        }
+    }
+
+    async fn insert(&self, key: &str, buf: &[u8]) {
+        let path = self.file(key);
+        let tmp = path.with_extension(Uuid::new_v4().to_simple().to_string());
+        let written = async {
+            fs::write(&tmp, buf).await?;
+            fs::rename(&tmp, &path).await
+        }
+        .await;
+        if let Err(e) = written {
+            warn!("unable to cache {}: {}", key, e);
+            let _ = fs::remove_file(&tmp).await;
+            return;
+        }
+        let evicted = self
+            .index
+            .lock()
+            .unwrap()
+            .insert(key.to_owned(), (), buf.len() as u64);
+        for (evicted, _) in evicted.into_iter().filter(|(k, _)| k != key) {
+            self.remove_file(&evicted).await;
+        }
+    }
+
+    async fn remove(&self, key: &str) {
+        if self.index.lock().unwrap().remove(key).is_some() {
+            self.remove_file(key).await;
+        }
+    }
+
+    async fn remove_file(&self, key: &str) {
+        if let Err(e) = fs::remove_file(self.file(key)).await {
+            warn!("unable to remove cached {}: {}", key, e);
+        }
+    }
+}
+
+/// The tiers shared between the storage and the streams it hands out.
+struct Tiers {
+    memory: Mutex<Lru<Hashed>>,
+    disk: Option<DiskCache>,
+    /// Bumped on every invalidation so loads racing a write don't cache what
+    /// they read before it.
+    generation: AtomicU64,
+}
+
+impl Tiers {
+    async fn get(&self, key: &str) -> Option<Hashed> {
+        if let Some(hashed) = self.memory.lock().unwrap().get(key) {
+            return Some(hashed.clone());
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        let hashed = Hashed::new(self.disk.as_ref()?.get(key).await?);
+        self.remember(key, &hashed, generation);
+        Some(hashed)
+    }
+
+    /// Stores what was loaded from the backend, unless invalidated since
+    /// `generation`.
+    async fn fill(&self, key: &str, hashed: &Hashed, generation: u64) {
+        if let Some(disk) = &self.disk {
+            if self.generation.load(Ordering::SeqCst) == generation {
+                disk.insert(key, &hashed.buf).await;
+                if self.generation.load(Ordering::SeqCst) != generation {
+                    disk.remove(key).await;
+                }
+            }
+        }
+        self.remember(key, hashed, generation);
+    }
+
+    fn remember(&self, key: &str, hashed: &Hashed, generation: u64) {
+        let mut memory = self.memory.lock().unwrap();
+        if self.generation.load(Ordering::SeqCst) == generation {
+            memory.insert(key.to_owned(), hashed.clone(), hashed.buf.len() as u64);
+        }
+    }
+
+    async fn invalidate(&self, key: &str) {
+        {
+            let mut memory = self.memory.lock().unwrap();
+            self.generation.fetch_add(1, Ordering::SeqCst);
+            memory.remove(key);
+        }
+        if let Some(disk) = &self.disk {
+            disk.remove(key).await;
+        }
+    }
+}
+
+/// Read-through cache in front of `inner`: an in-memory LRU and optionally a
+/// local disk tier. Writes through this storage invalidate the affected
+/// entries, writes by other processes are not noticed.
+pub struct CachingStorage<S: Storage> {
+    inner: S,
+    tiers: Arc<Tiers>,
+    /// Streams are only cached up to this size, larger ones are passed on
+    /// without buffering.
+    max_streamed: u64,
+}
+
+/// History and trash indexes are read, modified and written back by every
+/// replica, a stale copy would undo the changes of the others.
+fn cacheable(name: &str) -> bool {
+    !name.ends_with(".json")
+}
+
+fn cache_key(name: &str, prefix: &str, bucket: &str) -> String {
+    format!("{}/{}/{}", bucket, prefix, name)
+}
+
+impl<S: Storage> CachingStorage<S> {
+    pub fn new(inner: S, settings: &CacheSettings) -> Result<Self, Error> {
+        let disk = match &settings.disk_path {
+            Some(path) => Some(DiskCache::new(path, settings.disk_max_bytes)?),
+            None => None,
+        };
+        Ok(CachingStorage {
+            inner,
+            tiers: Arc::new(Tiers {
+                memory: Mutex::new(Lru::new(settings.memory_max_bytes)),
+                disk,
+                generation: AtomicU64::new(0),
+            }),
+            max_streamed: settings.memory_max_bytes,
+        })
+    }
+
+    async fn cached(&self, name: &str, prefix: &str, bucket: &str) -> Result<Hashed, Error> {
+        if !cacheable(name) {
+            return self.inner.load_hashed(name, prefix, bucket).await;
+        }
+        let key = cache_key(name, prefix, bucket);
+        if let Some(hashed) = self.tiers.get(&key).await {
+            return Ok(hashed);
+        }
+        let generation = self.tiers.generation.load(Ordering::SeqCst);
+        let hashed = self.inner.load_hashed(name, prefix, bucket).await?;
+        self.tiers.fill(&key, &hashed, generation).await;
+        Ok(hashed)
+    }
+
+    /// Streams from `inner`, collecting the chunks to fill the cache once
+    /// the stream is complete.
+    async fn streamed(&self, name: &str, prefix: &str, bucket: &str) -> Result<Streamed, Error> {
+        if !cacheable(name) {
+            return self.inner.load_stream(name, prefix, bucket).await;
+        }
+        let key = cache_key(name, prefix, bucket);
+        if let Some(hashed) = self.tiers.get(&key).await {
+            return Ok(hashed.into());
+        }
+        let generation = self.tiers.generation.load(Ordering::SeqCst);
+        let streamed = self.inner.load_stream(name, prefix, bucket).await?;
+        if streamed
+            .size
+            .map(|size| size > self.max_streamed)
+            .unwrap_or(true)
+        {
+            return Ok(streamed);
+        }
+        let tiers = Arc::clone(&self.tiers);
+        let stream = futures::stream::unfold(
+            (streamed.stream, Some(Vec::new())),
+            move |(mut stream, buf)| {
+                let tiers = Arc::clone(&tiers);
+                let key = key.clone();
+                async move {
+                    match stream.next().await {
+                        Some(Ok(chunk)) => {
+                            let buf = buf.map(|mut buf| {
+                                buf.extend_from_slice(&chunk);
+                                buf
+                            });
+                            Some((Ok(chunk), (stream, buf)))
+                        }
+                        // incomplete, nothing to cache
+                        Some(Err(e)) => Some((Err(e), (stream, None))),
+                        None => {
+                            if let Some(buf) = buf {
+                                tiers.fill(&key, &Hashed::new(buf), generation).await;
+                            }
+                            None
+                        }
+                    }
+                }
+            },
+        );
+        Ok(Streamed {
+            stream: Box::pin(stream),
+            ..streamed
+        })
+    }
+
+    async fn invalidate(&self, name: &str, prefix: &str, bucket: &str) {
+        self.tiers
+            .invalidate(&cache_key(name, prefix, bucket))
+            .await;
+    }
+}
+
+impl<S: Storage> Storage for CachingStorage<S> {
+    fn load(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move { Ok(self.cached(&name, &prefix, &bucket).await?.buf) })
+    }
+    fn load_hashed(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Hashed, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move { self.cached(&name, &prefix, &bucket).await })
+    }
+    fn load_stream(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Streamed, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move { self.streamed(&name, &prefix, &bucket).await })
+    }
+    fn load_version(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        version_id: Option<&str>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        match version_id {
+            None => self.load(name, prefix, bucket),
+            // previous versions are rarely asked for, no need to cache them
+            Some(_) => self.inner.load_version(name, prefix, bucket, version_id),
+        }
+    }
+    fn save(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        buf: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let res = self.inner.save(&name, &prefix, &bucket, buf).await;
+            self.invalidate(&name, &prefix, &bucket).await;
+            res
+        })
+    }
+    fn delete(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let res = self.inner.delete(&name, &prefix, &bucket).await;
+            self.invalidate(&name, &prefix, &bucket).await;
+            res
+        })
+    }
+    fn delete_many(
+        &self,
+        names: &[String],
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (names, prefix, bucket) = (names.to_vec(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let res = self.inner.delete_many(&names, &prefix, &bucket).await;
+            for name in &names {
+                self.invalidate(name, &prefix, &bucket).await;
+            }
+            res
+        })
+    }
+    fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>> {
+        self.inner.save_tmp(bucket, buf)
+    }
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        let (new_name, new_prefix) = (new_name.to_owned(), new_prefix.to_owned());
+        Box::pin(async move {
+            let res = self
+                .inner
+                .copy(&name, &prefix, &new_name, &new_prefix, &bucket)
+                .await;
+            self.invalidate(&new_name, &new_prefix, &bucket).await;
+            res
+        })
+    }
+    fn rename(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        let (new_name, new_prefix) = (new_name.to_owned(), new_prefix.to_owned());
+        Box::pin(async move {
+            let res = self
+                .inner
+                .rename(&name, &prefix, &new_name, &new_prefix, &bucket)
+                .await;
+            self.invalidate(&name, &prefix, &bucket).await;
+            self.invalidate(&new_name, &new_prefix, &bucket).await;
+            res
+        })
+    }
+    fn exists(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<bool, Error>> {
+        self.inner.exists(name, prefix, bucket)
+    }
+    fn stat(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Stat, Error>> {
+        self.inner.stat(name, prefix, bucket)
+    }
+    fn list(&self, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Vec<String>, Error>> {
+        self.inner.list(prefix, bucket)
+    }
+    fn versions(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<Version>, Error>> {
+        self.inner.versions(name, prefix, bucket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+    use futures::TryStreamExt;
+
+    fn settings(memory_max_bytes: u64, disk_path: Option<String>) -> CacheSettings {
+        CacheSettings {
+            memory_max_bytes,
+            disk_path,
+            disk_max_bytes: 1024,
+        }
+    }
+
+    #[test]
+    fn test_lru_evicts_least_recently_used() {
+        let mut lru = Lru::new(10);
+        assert!(lru.insert(String::from("a"), 1, 4).is_empty());
+        assert!(lru.insert(String::from("b"), 2, 4).is_empty());
+        assert_eq!(lru.get("a"), Some(&1));
+        assert_eq!(
+            lru.insert(String::from("c"), 3, 4),
+            vec![(String::from("b"), 2)]
+        );
+        assert_eq!(lru.get("b"), None);
+        assert_eq!(lru.bytes, 8);
+        // too large to be cached at all
+        assert!(lru.insert(String::from("d"), 4, 11).is_empty());
+        assert_eq!(lru.get("d"), None);
+    }
+
+    #[tokio::test]
+    async fn test_reads_are_cached_and_writes_invalidate() -> Result<(), Error> {
+        let storage = CachingStorage::new(MemoryStorage::default(), &settings(1024, None))?;
+        Storage::save(&storage, "a", "40", "b", b"old".to_vec()).await?;
+        assert_eq!(Storage::load(&storage, "a", "40", "b").await?, b"old");
+
+        // served from the cache, the backend isn't asked again
+        storage
+            .inner
+            .insert("a", "40", "b", b"changed behind our back".to_vec());
+        assert_eq!(Storage::load(&storage, "a", "40", "b").await?, b"old");
+
+        Storage::save(&storage, "a", "40", "b", b"new".to_vec()).await?;
+        assert_eq!(Storage::load(&storage, "a", "40", "b").await?, b"new");
+
+        Storage::rename(&storage, "a", "40", "c", "40", "b").await?;
+        assert!(Storage::load(&storage, "a", "40", "b").await.is_err());
+        assert_eq!(Storage::load(&storage, "c", "40", "b").await?, b"new");
+
+        Storage::delete(&storage, "c", "40", "b").await?;
+        assert!(Storage::load(&storage, "c", "40", "b").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_indexes_are_not_cached() -> Result<(), Error> {
+        let storage = CachingStorage::new(MemoryStorage::default(), &settings(1024, None))?;
+        Storage::save(&storage, "abc.json", "history", "b", b"[]".to_vec()).await?;
+        assert_eq!(
+            Storage::load(&storage, "abc.json", "history", "b").await?,
+            b"[]"
+        );
+        // written by another replica
+        storage
+            .inner
+            .insert("abc.json", "history", "b", b"[1]".to_vec());
+        assert_eq!(
+            Storage::load(&storage, "abc.json", "history", "b").await?,
+            b"[1]"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_streams_fill_the_cache() -> Result<(), Error> {
+        let storage = CachingStorage::new(MemoryStorage::default(), &settings(1024, None))?;
+        Storage::save(&storage, "a", "raw", "b", b"picture".to_vec()).await?;
+
+        // nothing is cached before the stream is read to the end
+        let streamed = Storage::load_stream(&storage, "a", "raw", "b").await?;
+        storage.inner.insert("a", "raw", "b", b"changed".to_vec());
+        let chunks: Vec<_> = streamed.stream.try_collect().await?;
+        assert_eq!(chunks.concat(), b"picture");
+
+        storage
+            .inner
+            .insert("a", "raw", "b", b"changed again".to_vec());
+        let streamed = Storage::load_stream(&storage, "a", "raw", "b").await?;
+        assert_eq!(streamed.sha256, Some(sha256_hex(b"picture")));
+        let chunks: Vec<_> = streamed.stream.try_collect().await?;
+        assert_eq!(chunks.concat(), b"picture");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_disk_tier() -> Result<(), Error> {
+        let dir = std::env::temp_dir().join(format!("fossil-cache-{}", Uuid::new_v4()));
+        let dir_s = dir.to_string_lossy().into_owned();
+        // nothing is kept in memory
+        let storage = CachingStorage::new(MemoryStorage::default(), &settings(0, Some(dir_s)))?;
+        Storage::save(&storage, "a", "40", "b", b"picture".to_vec()).await?;
+        assert_eq!(Storage::load(&storage, "a", "40", "b").await?, b"picture");
+        let file = storage.tiers.disk.as_ref().unwrap().file("b/40/a");
+        assert_eq!(std::fs::read(&file)?, b"picture");
+
+        storage.inner.insert("a", "40", "b", b"changed".to_vec());
+        assert_eq!(Storage::load(&storage, "a", "40", "b").await?, b"picture");
+
+        Storage::delete(&storage, "a", "40", "b").await?;
+        assert!(!file.exists());
+        std::fs::remove_dir_all(dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,492 @@
+use super::is_not_found;
+use super::name::InternalFileName;
+use super::sha256_hex;
+use super::Hashed;
+use super::Stat;
+use super::Storage;
+use super::StorageError;
+use super::Streamed;
+use super::Version;
+use actix_web::web::Bytes;
+use chrono::Duration;
+use chrono::Utc;
+use failure::Error;
+use futures::future::BoxFuture;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use log::info;
+use log::warn;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// Prefix of the content addressed blobs, named after their SHA-256.
+pub const BLOBS: &str = "blobs";
+
+/// Marks a pointer, followed by the hex encoded SHA-256 of the blob.
+const MAGIC: &[u8] = b"FSLB1";
+const POINTER_LEN: usize = 5 + 64;
+/// Upper bound of stored pointers, which an encrypting layer below seals.
+const MAX_STORED_POINTER_LEN: u64 = 256;
+
+fn pointer_to(sha256: &str) -> Vec<u8> {
+    [MAGIC, sha256.as_bytes()].concat()
+}
+
+fn is_unsupported(e: &Error) -> bool {
+    matches!(
+        e.downcast_ref::<StorageError>(),
+        Some(StorageError::VersionsUnsupported)
+    )
+}
+
+/// The blob `buf` points to, `None` for objects stored as they are.
+fn pointee(buf: &[u8]) -> Option<&str> {
+    if buf.len() != POINTER_LEN {
+        return None;
+    }
+    std::str::from_utf8(buf.strip_prefix(MAGIC)?)
+        .ok()
+        .filter(|sha256| sha256.bytes().all(|b| b.is_ascii_hexdigit()))
+}
+
+/// Stores pictures named after an `InternalFileName` once per content in
+/// `BLOBS` and a small pointer under their name. Copies and renames only move
+/// pointers. Everything else, and objects written before, is passed through.
+/// Stats, listings and versions describe the pointers.
+pub struct DedupStorage<S: Storage> {
+    inner: Arc<S>,
+}
+
+impl<S: Storage> Clone for DedupStorage<S> {
+    fn clone(&self) -> Self {
+        DedupStorage {
+            inner: Arc::clone(&self.inner),
+        }
+    }
+}
+
+impl<S: Storage> DedupStorage<S> {
+    pub fn new(inner: S) -> Self {
+        DedupStorage {
+            inner: Arc::new(inner),
+        }
+    }
+
+    async fn resolve(&self, buf: Vec<u8>, bucket: &str) -> Result<Vec<u8>, Error> {
+        match pointee(&buf) {
+            Some(sha256) => self.inner.load(sha256, BLOBS, bucket).await,
+            None => Ok(buf),
+        }
+    }
+
+    /// The stored contents of `name` and of its previous versions which may be
+    /// pointers. Objects removed since listing them have none.
+    async fn pointers(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> Result<Vec<Vec<u8>>, Error> {
+        let mut pointers = vec![];
+        match self.inner.stat(name, prefix, bucket).await {
+            Ok(stat) if stat.size <= MAX_STORED_POINTER_LEN => {
+                match self.inner.load(name, prefix, bucket).await {
+                    Ok(buf) => pointers.push(buf),
+                    Err(e) if is_not_found(&e) => (),
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(_) => (),
+            Err(e) if is_not_found(&e) => (),
+            Err(e) => return Err(e),
+        }
+        let versions = match self.inner.versions(name, prefix, bucket).await {
+            Ok(versions) => versions,
+            Err(e) if is_unsupported(&e) || is_not_found(&e) => vec![],
+            Err(e) => return Err(e),
+        };
+        for version in versions {
+            if version.latest || version.size > MAX_STORED_POINTER_LEN {
+                continue;
+            }
+            match self
+                .inner
+                .load_version(name, prefix, bucket, Some(&version.version_id))
+                .await
+            {
+                Ok(buf) => pointers.push(buf),
+                Err(e) if is_not_found(&e) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(pointers)
+    }
+
+    /// Counts the pointers to every blob in `prefixes` and deletes the blobs
+    /// nothing points to which are older than `grace`. Returns the number of
+    /// deleted blobs.
+    pub async fn collect_garbage(
+        &self,
+        bucket: &str,
+        prefixes: &[String],
+        grace: Duration,
+    ) -> Result<usize, Error> {
+        let cutoff = Utc::now() - grace;
+        // blobs written after listing are no candidates
+        let mut refs: HashMap<String, usize> = self
+            .inner
+            .list(BLOBS, bucket)
+            .await?
+            .into_iter()
+            .map(|blob| (blob, 0))
+            .collect();
+        for prefix in prefixes {
+            for name in self.inner.list(prefix, bucket).await? {
+                for buf in self.pointers(&name, prefix, bucket).await? {
+                    if let Some(count) = pointee(&buf).and_then(|sha256| refs.get_mut(sha256)) {
+                        *count += 1;
+                    }
+                }
+            }
+        }
+        let mut collected = 0;
+        for (blob, _) in refs.into_iter().filter(|(_, count)| *count == 0) {
+            match self.inner.stat(&blob, BLOBS, bucket).await {
+                Ok(Stat {
+                    modified: Some(modified),
+                    ..
+                }) if modified < cutoff => {
+                    self.inner.delete(&blob, BLOBS, bucket).await?;
+                    collected += 1;
+                }
+                Ok(_) => (),
+                Err(e) => warn!("unable to stat blob {}: {}", blob, e),
+            }
+        }
+        if collected > 0 {
+            info!("collected {} unreferenced blobs from {}", collected, bucket);
+        }
+        Ok(collected)
+    }
+}
+
+impl<S: Storage> Storage for DedupStorage<S> {
+    fn load(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let buf = self.inner.load(&name, &prefix, &bucket).await?;
+            self.resolve(buf, &bucket).await
+        })
+    }
+    /// Blobs are named after their hash.
+    fn load_hashed(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Hashed, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let hashed = self.inner.load_hashed(&name, &prefix, &bucket).await?;
+            match pointee(&hashed.buf) {
+                Some(sha256) => Ok(Hashed {
+                    buf: self.inner.load(sha256, BLOBS, &bucket).await?,
+                    sha256: sha256.to_owned(),
+                }),
+                None => Ok(hashed),
+            }
+        })
+    }
+    fn load_version(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        version_id: Option<&str>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        let version_id = version_id.map(String::from);
+        Box::pin(async move {
+            let buf = self
+                .inner
+                .load_version(&name, &prefix, &bucket, version_id.as_deref())
+                .await?;
+            self.resolve(buf, &bucket).await
+        })
+    }
+    /// Reads just enough of the object to tell a pointer from a picture.
+    fn load_stream(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Streamed, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let mut streamed = self.inner.load_stream(&name, &prefix, &bucket).await?;
+            if streamed.size.is_some() && streamed.size != Some(POINTER_LEN as u64) {
+                return Ok(streamed);
+            }
+            let mut head = Vec::new();
+            let mut ended = false;
+            while head.len() <= POINTER_LEN {
+                match streamed.stream.try_next().await? {
+                    Some(chunk) => head.extend_from_slice(&chunk),
+                    None => {
+                        ended = true;
+                        break;
+                    }
+                }
+            }
+            if let Some(sha256) = pointee(&head).filter(|_| ended) {
+                let blob = self.inner.load_stream(sha256, BLOBS, &bucket).await?;
+                return Ok(Streamed {
+                    sha256: blob.sha256.or_else(|| Some(sha256.to_owned())),
+                    ..blob
+                });
+            }
+            let head = futures::stream::once(futures::future::ok(Bytes::from(head)));
+            Ok(Streamed {
+                stream: Box::pin(head.chain(streamed.stream)),
+                ..streamed
+            })
+        })
+    }
+    /// Derivatives are stored as blob and pointer. Rewriting an existing blob
+    /// refreshes it for the garbage collector.
+    fn save(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        buf: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        if InternalFileName::from_name(name).is_none() {
+            return self.inner.save(name, prefix, bucket, buf);
+        }
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let sha256 = sha256_hex(&buf);
+            self.inner.save(&sha256, BLOBS, &bucket, buf).await?;
+            self.inner
+                .save(&name, &prefix, &bucket, pointer_to(&sha256))
+                .await
+        })
+    }
+    fn delete(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<(), Error>> {
+        self.inner.delete(name, prefix, bucket)
+    }
+    fn delete_many(
+        &self,
+        names: &[String],
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        self.inner.delete_many(names, prefix, bucket)
+    }
+    fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>> {
+        self.inner.save_tmp(bucket, buf)
+    }
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        self.inner.copy(name, prefix, new_name, new_prefix, bucket)
+    }
+    fn rename(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        self.inner
+            .rename(name, prefix, new_name, new_prefix, bucket)
+    }
+    fn exists(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<bool, Error>> {
+        self.inner.exists(name, prefix, bucket)
+    }
+    fn stat(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Stat, Error>> {
+        self.inner.stat(name, prefix, bucket)
+    }
+    fn list(&self, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Vec<String>, Error>> {
+        self.inner.list(prefix, bucket)
+    }
+    fn versions(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<Version>, Error>> {
+        self.inner.versions(name, prefix, bucket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::EncryptionSettings;
+    use crate::storage::encrypted::EncryptedStorage;
+    use crate::storage::encrypted::Keyring;
+    use crate::storage::memory::MemoryStorage;
+    use cis_profile::schema::Display;
+
+    fn derivative(uuid: &str) -> String {
+        InternalFileName::from_uuid_and_display(uuid, &Display::Public, None).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_identical_derivatives_share_a_blob() -> Result<(), Error> {
+        let storage = DedupStorage::new(MemoryStorage::default());
+        let (a, b) = (derivative("a"), derivative("b"));
+        Storage::save(&storage, &a, "264", "bucket", vec![1, 2, 3]).await?;
+        Storage::save(&storage, &b, "264", "bucket", vec![1, 2, 3]).await?;
+        Storage::copy(&storage, &a, "264", &a, "history/264", "bucket").await?;
+
+        assert_eq!(storage.inner.list(BLOBS, "bucket").await?.len(), 1);
+        assert_eq!(
+            storage.inner.load(&a, "264", "bucket").await?.len(),
+            POINTER_LEN
+        );
+        assert_eq!(
+            Storage::load(&storage, &b, "264", "bucket").await?,
+            vec![1, 2, 3]
+        );
+        assert_eq!(
+            Storage::load(&storage, &a, "history/264", "bucket").await?,
+            vec![1, 2, 3]
+        );
+        let hashed = Storage::load_hashed(&storage, &a, "264", "bucket").await?;
+        assert_eq!(hashed, Hashed::new(vec![1, 2, 3]));
+
+        let streamed = Storage::load_stream(&storage, &a, "264", "bucket").await?;
+        assert_eq!(streamed.sha256, Some(hashed.sha256));
+        let chunks: Vec<_> = streamed.stream.try_collect().await?;
+        assert_eq!(chunks.concat(), vec![1, 2, 3]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_other_objects_are_passed_through() -> Result<(), Error> {
+        let storage = DedupStorage::new(MemoryStorage::default());
+        Storage::save(&storage, "index.json", "history", "bucket", vec![1]).await?;
+        // stored before deduplication
+        storage
+            .inner
+            .insert(&derivative("a"), "264", "bucket", vec![2]);
+
+        assert!(storage.inner.list(BLOBS, "bucket").await?.is_empty());
+        assert_eq!(
+            Storage::load(&storage, "index.json", "history", "bucket").await?,
+            vec![1]
+        );
+        let streamed = Storage::load_stream(&storage, &derivative("a"), "264", "bucket").await?;
+        let chunks: Vec<_> = streamed.stream.try_collect().await?;
+        assert_eq!(chunks.concat(), vec![2]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_collect_unreferenced_blobs() -> Result<(), Error> {
+        let storage = DedupStorage::new(MemoryStorage::default());
+        let (a, b) = (derivative("a"), derivative("b"));
+        Storage::save(&storage, &a, "264", "bucket", vec![1]).await?;
+        Storage::save(&storage, &b, "264", "bucket", vec![2]).await?;
+        Storage::rename(&storage, &b, "264", &b, "trash/264", "bucket").await?;
+        Storage::delete(&storage, &a, "264", "bucket").await?;
+        let prefixes = vec![String::from("264"), String::from("trash/264")];
+
+        // too young
+        assert_eq!(
+            storage
+                .collect_garbage("bucket", &prefixes, Duration::minutes(60))
+                .await?,
+            0
+        );
+        assert_eq!(
+            storage
+                .collect_garbage("bucket", &prefixes, Duration::minutes(-1))
+                .await?,
+            1
+        );
+        assert_eq!(storage.inner.list(BLOBS, "bucket").await?.len(), 1);
+        assert_eq!(
+            Storage::load(&storage, &b, "trash/264", "bucket").await?,
+            vec![2]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_previous_versions_keep_their_blobs() -> Result<(), Error> {
+        let storage = DedupStorage::new(MemoryStorage::default().versioned());
+        let a = derivative("a");
+        Storage::save(&storage, &a, "264", "bucket", vec![1]).await?;
+        Storage::save(&storage, &a, "264", "bucket", vec![2]).await?;
+        let prefixes = vec![String::from("264")];
+
+        assert_eq!(
+            storage
+                .collect_garbage("bucket", &prefixes, Duration::minutes(-1))
+                .await?,
+            0
+        );
+        let versions = Storage::versions(&storage, &a, "264", "bucket").await?;
+        assert_eq!(
+            Storage::load_version(&storage, &a, "264", "bucket", Some(&versions[1].version_id))
+                .await?,
+            vec![1]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_deduplicates_above_encryption() -> Result<(), Error> {
+        let settings = EncryptionSettings {
+            active_key_id: String::from("a"),
+            keys: vec![(String::from("a"), base64::encode([1; 32]))]
+                .into_iter()
+                .collect(),
+            allow_plaintext: false,
+        };
+        let storage = DedupStorage::new(EncryptedStorage::new(
+            MemoryStorage::default(),
+            Keyring::from_settings(&settings)?,
+        ));
+        let private = |uuid| InternalFileName::from_uuid_and_display(uuid, &Display::Private, None);
+        let (a, b) = (private("a").to_string(), private("b").to_string());
+        Storage::save(&storage, &a, "264", "bucket", b"picture".to_vec()).await?;
+        Storage::save(&storage, &b, "264", "bucket", b"picture".to_vec()).await?;
+
+        let blobs = storage.inner.list(BLOBS, "bucket").await?;
+        assert_eq!(blobs.len(), 1);
+        // the blob itself is sealed
+        assert!(
+            Storage::load(storage.inner.inner(), &blobs[0], BLOBS, "bucket")
+                .await?
+                .starts_with(b"FSLE")
+        );
+        let prefixes = vec![String::from("264")];
+        assert_eq!(
+            storage
+                .collect_garbage("bucket", &prefixes, Duration::minutes(-1))
+                .await?,
+            0
+        );
+        assert_eq!(
+            Storage::load(&storage, &b, "264", "bucket").await?,
+            b"picture"
+        );
+        Ok(())
+    }
+}
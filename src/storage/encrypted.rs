@@ -0,0 +1,465 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
+use super::dedup::BLOBS;
+use super::name::InternalFileName;
+use super::Stat;
+use super::Storage;
+use super::Version;
+use crate::settings::EncryptionSettings;
+use aes_gcm::aead::Aead;
+use aes_gcm::aead::NewAead;
+use aes_gcm::aead::Payload;
+use aes_gcm::Aes256Gcm;
+use aes_gcm::Key;
+use aes_gcm::Nonce;
+use cis_profile::schema::Display;
+use failure::Error;
+use futures::future::BoxFuture;
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+/// Marks an encrypted object, followed by the key id length, the key id, the
+/// nonce and the ciphertext. Version 2 binds the ciphertext to its owner as
+/// associated data, version 1 objects are still opened.
+const MAGIC: &[u8] = b"FSLE2";
+const MAGIC_V1: &[u8] = b"FSLE1";
+const NONCE_LEN: usize = 12;
+
+#[derive(Debug, Fail)]
+pub enum EncryptionError {
+    #[fail(display = "invalid data key {}", _0)]
+    InvalidKey(String),
+    #[fail(display = "no data key with id {}", _0)]
+    UnknownKey(String),
+    #[fail(display = "unable to encrypt")]
+    Encrypt,
+    #[fail(display = "unable to decrypt")]
+    Decrypt,
+    #[fail(display = "malformed envelope")]
+    Malformed,
+    #[fail(display = "{} is stored unencrypted", _0)]
+    Unsealed(String),
+}
+
+/// The AES-256-GCM data keys by id. New objects are encrypted with the active
+/// key, the others are kept to decrypt what was stored before a rotation.
+pub struct Keyring {
+    active: String,
+    keys: HashMap<String, Aes256Gcm>,
+    allow_plaintext: bool,
+}
+
+impl Keyring {
+    pub fn from_settings(settings: &EncryptionSettings) -> Result<Self, Error> {
+        let mut keys = HashMap::new();
+        for (id, key) in &settings.keys {
+            let key: [u8; 32] = base64::decode(key)
+                .ok()
+                .and_then(|key| key.try_into().ok())
+                .filter(|_| id.len() <= u8::MAX as usize)
+                .ok_or_else(|| EncryptionError::InvalidKey(id.clone()))?;
+            keys.insert(id.clone(), Aes256Gcm::new(&Key::from(key)));
+        }
+        if !keys.contains_key(&settings.active_key_id) {
+            return Err(EncryptionError::UnknownKey(settings.active_key_id.clone()).into());
+        }
+        Ok(Keyring {
+            active: settings.active_key_id.clone(),
+            keys,
+            allow_plaintext: settings.allow_plaintext,
+        })
+    }
+
+    fn seal(&self, buf: &[u8], owner: &str) -> Result<Vec<u8>, Error> {
+        let cipher = &self.keys[&self.active];
+        let nonce: [u8; NONCE_LEN] = rand::random();
+        let payload = Payload {
+            msg: buf,
+            aad: owner.as_bytes(),
+        };
+        let ciphertext = cipher
+            .encrypt(&Nonce::from(nonce), payload)
+            .map_err(|_| EncryptionError::Encrypt)?;
+        let mut sealed =
+            Vec::with_capacity(MAGIC.len() + 1 + self.active.len() + NONCE_LEN + ciphertext.len());
+        sealed.extend_from_slice(MAGIC);
+        sealed.push(self.active.len() as u8);
+        sealed.extend_from_slice(self.active.as_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&ciphertext);
+        Ok(sealed)
+    }
+
+    /// Decrypts sealed objects of `owner`. Plain objects are only returned if
+    /// they don't need to be sealed or plaintext is allowed.
+    fn open(&self, buf: Vec<u8>, owner: &str, required: bool) -> Result<Vec<u8>, Error> {
+        let (rest, aad) = match (buf.strip_prefix(MAGIC), buf.strip_prefix(MAGIC_V1)) {
+            (Some(rest), _) => (rest, owner.as_bytes()),
+            (None, Some(rest)) => (rest, &[][..]),
+            (None, None) if required && !self.allow_plaintext => {
+                return Err(EncryptionError::Unsealed(owner.to_owned()).into())
+            }
+            (None, None) => return Ok(buf),
+        };
+        let (&id_len, rest) = rest.split_first().ok_or(EncryptionError::Malformed)?;
+        if rest.len() < id_len as usize + NONCE_LEN {
+            return Err(EncryptionError::Malformed.into());
+        }
+        let (id, rest) = rest.split_at(id_len as usize);
+        let (nonce, ciphertext) = rest.split_at(NONCE_LEN);
+        let nonce: [u8; NONCE_LEN] = nonce.try_into().map_err(|_| EncryptionError::Malformed)?;
+        let id = String::from_utf8_lossy(id);
+        let cipher = self
+            .keys
+            .get(id.as_ref())
+            .ok_or_else(|| EncryptionError::UnknownKey(id.to_string()))?;
+        let payload = Payload {
+            msg: ciphertext,
+            aad,
+        };
+        Ok(cipher
+            .decrypt(&Nonce::from(nonce), payload)
+            .map_err(|_| EncryptionError::Decrypt)?)
+    }
+}
+
+fn is_sealed(buf: &[u8]) -> bool {
+    buf.starts_with(MAGIC) || buf.starts_with(MAGIC_V1)
+}
+
+/// What a sealed object is bound to: the uuid hash of avatar and history
+/// names, the name itself for blobs. Ciphertext moved to another avatar fails
+/// to decrypt.
+fn owner(name: &str) -> &str {
+    match name.rsplit_once('_') {
+        Some((uuid_hash, _)) => uuid_hash,
+        None => name,
+    }
+}
+
+/// Whether an object gets encrypted: pictures above `Display::Public` by the
+/// display level in their name and all deduplicated blobs, which might be
+/// shared with those.
+fn sealed(name: &str, prefix: &str) -> bool {
+    prefix == BLOBS
+        || InternalFileName::from_name(name)
+            .map(|internal| internal.display > Display::Public)
+            .unwrap_or_default()
+}
+
+/// Encrypts pictures above `Display::Public` before handing them to `inner`.
+/// Loads decrypt whatever carries the envelope header, so objects keep
+/// working after changing their display level.
+pub struct EncryptedStorage<S: Storage> {
+    inner: S,
+    keyring: Keyring,
+}
+
+impl<S: Storage> EncryptedStorage<S> {
+    pub fn new(inner: S, keyring: Keyring) -> Self {
+        EncryptedStorage { inner, keyring }
+    }
+
+    #[cfg(test)]
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Copies through the service, decrypting for the old owner and sealing
+    /// for the new one. Sealed objects stay sealed.
+    async fn transfer(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> Result<(), Error> {
+        let buf = self.inner.load(name, prefix, bucket).await?;
+        let was_sealed = is_sealed(&buf);
+        let buf = self.keyring.open(buf, owner(name), sealed(name, prefix))?;
+        let buf = if was_sealed || sealed(new_name, new_prefix) {
+            self.keyring.seal(&buf, owner(new_name))?
+        } else {
+            buf
+        };
+        self.inner.save(new_name, new_prefix, bucket, buf).await
+    }
+}
+
+/// Ciphertext is copied as is within the same owner, only plain objects
+/// becoming sealed ones or objects changing owners pass through the service.
+fn passes_through(name: &str, prefix: &str, new_name: &str, new_prefix: &str) -> bool {
+    owner(name) != owner(new_name) || (!sealed(name, prefix) && sealed(new_name, new_prefix))
+}
+
+impl<S: Storage> Storage for EncryptedStorage<S> {
+    fn load(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        let load = self.inner.load(name, prefix, bucket);
+        let (owner, required) = (owner(name).to_owned(), sealed(name, prefix));
+        Box::pin(async move { self.keyring.open(load.await?, &owner, required) })
+    }
+    fn load_version(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        version_id: Option<&str>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        let load = self.inner.load_version(name, prefix, bucket, version_id);
+        let (owner, required) = (owner(name).to_owned(), sealed(name, prefix));
+        Box::pin(async move { self.keyring.open(load.await?, &owner, required) })
+    }
+    fn save(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        buf: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        if !sealed(name, prefix) {
+            return self.inner.save(name, prefix, bucket, buf);
+        }
+        let name = name.to_owned();
+        let prefix = prefix.to_owned();
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            let buf = self.keyring.seal(&buf, owner(&name))?;
+            self.inner.save(&name, &prefix, &bucket, buf).await
+        })
+    }
+    fn delete(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<(), Error>> {
+        self.inner.delete(name, prefix, bucket)
+    }
+    fn delete_many(
+        &self,
+        names: &[String],
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        self.inner.delete_many(names, prefix, bucket)
+    }
+    fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>> {
+        self.inner.save_tmp(bucket, buf)
+    }
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        if !passes_through(name, prefix, new_name, new_prefix) {
+            return self.inner.copy(name, prefix, new_name, new_prefix, bucket);
+        }
+        let name = name.to_owned();
+        let prefix = prefix.to_owned();
+        let new_name = new_name.to_owned();
+        let new_prefix = new_prefix.to_owned();
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            self.transfer(&name, &prefix, &new_name, &new_prefix, &bucket)
+                .await
+        })
+    }
+    fn rename(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        if !passes_through(name, prefix, new_name, new_prefix) {
+            return self
+                .inner
+                .rename(name, prefix, new_name, new_prefix, bucket);
+        }
+        let name = name.to_owned();
+        let prefix = prefix.to_owned();
+        let new_name = new_name.to_owned();
+        let new_prefix = new_prefix.to_owned();
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            self.copy(&name, &prefix, &new_name, &new_prefix, &bucket)
+                .await?;
+            self.delete(&name, &prefix, &bucket).await
+        })
+    }
+    fn exists(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<bool, Error>> {
+        self.inner.exists(name, prefix, bucket)
+    }
+    fn stat(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Stat, Error>> {
+        self.inner.stat(name, prefix, bucket)
+    }
+    fn list(&self, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Vec<String>, Error>> {
+        self.inner.list(prefix, bucket)
+    }
+    fn versions(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<Version>, Error>> {
+        self.inner.versions(name, prefix, bucket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    fn settings(active: &str, keys: &[(&str, u8)]) -> EncryptionSettings {
+        EncryptionSettings {
+            active_key_id: active.to_owned(),
+            keys: keys
+                .iter()
+                .map(|(id, k)| (String::from(*id), base64::encode([*k; 32])))
+                .collect(),
+            allow_plaintext: false,
+        }
+    }
+
+    fn name(display: &Display) -> String {
+        InternalFileName::from_uuid_and_display("some-uuid", display, None).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_only_non_public_is_sealed() -> Result<(), Error> {
+        let keyring = Keyring::from_settings(&settings("a", &[("a", 1)]))?;
+        let storage = EncryptedStorage::new(MemoryStorage::default(), keyring);
+        let private = name(&Display::Private);
+        let public = name(&Display::Public);
+        Storage::save(&storage, &private, "264", "b", b"private".to_vec()).await?;
+        Storage::save(&storage, &public, "264", "b", b"public".to_vec()).await?;
+
+        let raw = Storage::load(&storage.inner, &private, "264", "b").await?;
+        assert!(raw.starts_with(MAGIC));
+        assert!(!raw.windows(7).any(|w| w == b"private"));
+        assert_eq!(
+            Storage::load(&storage.inner, &public, "264", "b").await?,
+            b"public"
+        );
+        assert_eq!(
+            Storage::load(&storage, &private, "264", "b").await?,
+            b"private"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rotated_keys_still_decrypt() -> Result<(), Error> {
+        let private = name(&Display::Staff);
+        let old = EncryptedStorage::new(
+            MemoryStorage::default(),
+            Keyring::from_settings(&settings("a", &[("a", 1)]))?,
+        );
+        Storage::save(&old, &private, "264", "b", b"staff".to_vec()).await?;
+
+        let rotated = EncryptedStorage::new(
+            old.inner,
+            Keyring::from_settings(&settings("b", &[("a", 1), ("b", 2)]))?,
+        );
+        assert_eq!(
+            Storage::load(&rotated, &private, "264", "b").await?,
+            b"staff"
+        );
+
+        let retired = EncryptedStorage::new(
+            rotated.inner,
+            Keyring::from_settings(&settings("b", &[("b", 2)]))?,
+        );
+        assert!(Storage::load(&retired, &private, "264", "b").await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rename_to_non_public_seals() -> Result<(), Error> {
+        let keyring = Keyring::from_settings(&settings("a", &[("a", 1)]))?;
+        let storage = EncryptedStorage::new(MemoryStorage::default(), keyring);
+        let public = name(&Display::Public);
+        let vouched = name(&Display::Vouched);
+        Storage::save(&storage, &public, "264", "b", b"picture".to_vec()).await?;
+        Storage::rename(&storage, &public, "264", &vouched, "264", "b").await?;
+        assert!(!Storage::exists(&storage, &public, "264", "b").await?);
+        assert!(Storage::load(&storage.inner, &vouched, "264", "b")
+            .await?
+            .starts_with(MAGIC));
+        assert_eq!(
+            Storage::load(&storage, &vouched, "264", "b").await?,
+            b"picture"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sealed_objects_are_bound_to_their_owner() -> Result<(), Error> {
+        let keyring = Keyring::from_settings(&settings("a", &[("a", 1)]))?;
+        let storage = EncryptedStorage::new(MemoryStorage::default(), keyring);
+        let mine = name(&Display::Staff);
+        let theirs = InternalFileName::from_uuid_and_display("other-uuid", &Display::Staff, None)
+            .to_string();
+        Storage::save(&storage, &mine, "264", "b", b"mine".to_vec()).await?;
+        Storage::copy(&storage.inner, &mine, "264", &theirs, "264", "b").await?;
+        assert!(Storage::load(&storage, &theirs, "264", "b").await.is_err());
+
+        // moving to another owner goes through the service
+        let history = format!("{}_1.png", owner(&theirs));
+        Storage::copy(&storage, &mine, "264", &history, "history/264", "b").await?;
+        assert!(Storage::load(&storage.inner, &history, "history/264", "b")
+            .await?
+            .starts_with(MAGIC));
+        assert_eq!(
+            Storage::load(&storage, &history, "history/264", "b").await?,
+            b"mine"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_plaintext_and_v1_envelopes() -> Result<(), Error> {
+        let private = name(&Display::Private);
+        let inner = MemoryStorage::default();
+        inner.insert(&private, "264", "b", b"plain".to_vec());
+        let cipher = Aes256Gcm::new(&Key::from([1; 32]));
+        let ciphertext = cipher
+            .encrypt(&Nonce::from([0; NONCE_LEN]), &b"v1"[..])
+            .unwrap();
+        let v1 = [MAGIC_V1, &[1], b"a", &[0; NONCE_LEN], &ciphertext].concat();
+        inner.insert(&private, "528", "b", v1);
+
+        let storage =
+            EncryptedStorage::new(inner, Keyring::from_settings(&settings("a", &[("a", 1)]))?);
+        assert!(Storage::load(&storage, &private, "264", "b").await.is_err());
+        assert_eq!(Storage::load(&storage, &private, "528", "b").await?, b"v1");
+
+        let mut migrating = settings("a", &[("a", 1)]);
+        migrating.allow_plaintext = true;
+        let storage = EncryptedStorage::new(storage.inner, Keyring::from_settings(&migrating)?);
+        assert_eq!(
+            Storage::load(&storage, &private, "264", "b").await?,
+            b"plain"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_settings() {
+        assert!(Keyring::from_settings(&settings("c", &[("a", 1)])).is_err());
+        let mut short_key = settings("a", &[]);
+        short_key
+            .keys
+            .insert(String::from("a"), base64::encode([0; 16]));
+        assert!(Keyring::from_settings(&short_key).is_err());
+    }
+}
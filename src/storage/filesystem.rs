@@ -0,0 +1,932 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
+use super::sha256_hex;
+use super::Stat;
+use super::Storage;
+use super::Streamed;
+use super::TMP;
+use actix_web::web::Bytes;
+use async_std::fs;
+use async_std::prelude::*;
+use chrono::DateTime;
+use chrono::Utc;
+use failure::Error;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use futures::TryFutureExt;
+use log::error;
+use log::info;
+use log::warn;
+use std::path::Component;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Stores `name` under `bucket/prefix/ab/cd/name`. Files of the previous flat
+/// `bucket/{prefix}-{name}` layout are still found until they are migrated.
+pub struct FilesystemStorage {
+    pub path: Arc<PathBuf>,
+}
+
+#[derive(Debug, Fail)]
+pub enum FilesystemError {
+    #[fail(display = "path leaves the storage root")]
+    OutsideRoot,
+}
+
+/// The two directory levels below the prefix: the first four hex digits of
+/// the uuid hash (or uuid) a name starts with, or of its SHA-256 for names
+/// like `index.json`.
+fn shard(name: &str) -> (String, String) {
+    let hex = match name.get(..4) {
+        Some(head) if head.bytes().all(|b| b.is_ascii_hexdigit()) => head.to_owned(),
+        _ => sha256_hex(name.as_bytes())[..4].to_owned(),
+    };
+    (hex[..2].to_owned(), hex[2..].to_owned())
+}
+
+/// Names of the files (or directories) in `dir`, nothing if it is missing.
+/// Hidden files are partial writes.
+async fn entries(dir: &Path, files: bool) -> Result<Vec<String>, Error> {
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next().await {
+        let entry = entry?;
+        let file_type = entry.file_type().await?;
+        if (files && !file_type.is_file()) || (!files && !file_type.is_dir()) {
+            continue;
+        }
+        if let Some(name) = entry.file_name().to_str() {
+            if !name.starts_with('.') {
+                names.push(name.to_owned());
+            }
+        }
+    }
+    Ok(names)
+}
+
+/// Removes `path`, a missing file is fine.
+async fn remove(path: &Path) -> Result<(), Error> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// Writes `buf` to a hidden sibling of `path`, syncs it and renames it into
+/// place. Readers see either the old or the complete new file.
+async fn write_atomic(path: &Path, buf: &[u8]) -> Result<(), Error> {
+    let dir = path.parent().map(PathBuf::from).unwrap_or_default();
+    let file_name = path
+        .file_name()
+        .map(|f| f.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let tmp = dir.join(format!(".{}.{}", file_name, Uuid::new_v4().to_simple()));
+
+    let written = async {
+        let mut file = fs::OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&tmp)
+            .await?;
+        file.write_all(buf).await?;
+        file.sync_all().await?;
+        fs::rename(&tmp, path).await
+    }
+    .await;
+    if let Err(err) = written {
+        if let Err(e) = fs::remove_file(&tmp).await {
+            warn!("unable to remove {}: {}", tmp.display(), e);
+        }
+        return Err(err.into());
+    }
+
+    // persist the rename itself
+    #[cfg(unix)]
+    fs::File::open(&dir).await?.sync_all().await?;
+
+    Ok(())
+}
+
+impl FilesystemStorage {
+    /// Rejects `..`, absolute paths and the like before touching the disk.
+    fn checked(&self, relative: PathBuf) -> Result<PathBuf, Error> {
+        if relative
+            .components()
+            .all(|component| matches!(component, Component::Normal(_)))
+        {
+            Ok(self.path.join(relative))
+        } else {
+            Err(FilesystemError::OutsideRoot.into())
+        }
+    }
+
+    fn file_path(&self, name: &str, prefix: &str, bucket: &str) -> Result<PathBuf, Error> {
+        let (first, second) = shard(name);
+        self.checked(
+            Path::new(bucket)
+                .join(prefix)
+                .join(first)
+                .join(second)
+                .join(name),
+        )
+    }
+
+    fn flat_path(&self, name: &str, prefix: &str, bucket: &str) -> Result<PathBuf, Error> {
+        self.checked(Path::new(bucket).join(format!("{prefix}-{name}")))
+    }
+
+    /// Canonicalizes the deepest existing ancestor of `path` to make sure no
+    /// symlink leads outside of the root.
+    async fn resolve(&self, path: PathBuf) -> Result<PathBuf, Error> {
+        let root = match fs::canonicalize(self.path.as_path()).await {
+            Ok(root) => root,
+            // nothing below a missing root can point elsewhere
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(path),
+            Err(err) => return Err(err.into()),
+        };
+        let mut existing = path.as_path();
+        loop {
+            match fs::canonicalize(existing).await {
+                Ok(real) if real.starts_with(&root) => return Ok(path),
+                Ok(_) => return Err(FilesystemError::OutsideRoot.into()),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                    existing = existing.parent().ok_or(FilesystemError::OutsideRoot)?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+    }
+
+    /// The sharded path of an object, or its flat one if only that exists.
+    async fn locate(&self, name: &str, prefix: &str, bucket: &str) -> Result<PathBuf, Error> {
+        let path = self.resolve(self.file_path(name, prefix, bucket)?).await?;
+        if fs::metadata(&path).await.is_ok() {
+            return Ok(path);
+        }
+        let flat = self.resolve(self.flat_path(name, prefix, bucket)?).await?;
+        if fs::metadata(&flat).await.is_ok() {
+            Ok(flat)
+        } else {
+            Ok(path)
+        }
+    }
+
+    /// Drops the flat copy of an object that was just written sharded, so
+    /// both never exist at once.
+    async fn remove_flat(&self, name: &str, prefix: &str, bucket: &str) -> Result<(), Error> {
+        remove(&self.resolve(self.flat_path(name, prefix, bucket)?).await?).await
+    }
+
+    /// Moves every file of the flat layout below `bucket` into its shard.
+    /// Safe to run while serving and to run again. Returns the number of
+    /// moved files.
+    pub async fn migrate_flat(&self, bucket: &str) -> Result<usize, Error> {
+        let mut moved = 0;
+        let mut dirs = vec![Vec::<String>::new()];
+        while let Some(dir) = dirs.pop() {
+            let path = self
+                .resolve(
+                    self.checked(
+                        dir.iter()
+                            .fold(PathBuf::from(bucket), |path, segment| path.join(segment)),
+                    )?,
+                )
+                .await?;
+            for file in entries(&path, true).await? {
+                let (first, second) = shard(&file);
+                if dir.len() >= 2 && dir[dir.len() - 2..] == [first, second] {
+                    continue;
+                }
+                let (last, name) = match file.split_once('-') {
+                    Some(split) => split,
+                    None => continue,
+                };
+                let prefix = dir
+                    .iter()
+                    .map(String::as_str)
+                    .chain(std::iter::once(last))
+                    .collect::<Vec<_>>()
+                    .join("/");
+                self.rename(name, &prefix, name, &prefix, bucket).await?;
+                moved += 1;
+            }
+            for sub in entries(&path, false).await? {
+                let mut sub_dir = dir.clone();
+                sub_dir.push(sub);
+                dirs.push(sub_dir);
+            }
+        }
+        if moved > 0 {
+            info!("moved {} files of {} into shards", moved, bucket);
+        }
+        Ok(moved)
+    }
+}
+
+impl Storage for FilesystemStorage {
+    fn load(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        info!("reading file in bucket '{}'", bucket);
+
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+
+        Box::pin(async move {
+            let path = self.locate(&name, &prefix, &bucket).await?;
+            Ok(fs::read(path).await?)
+        })
+    }
+
+    fn load_stream(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Streamed, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+
+        Box::pin(async move {
+            let path = self.locate(&name, &prefix, &bucket).await?;
+            let file = fs::File::open(path).await?;
+            let size = file.metadata().await?.len();
+            let stream = futures::stream::try_unfold(file, |mut file| async move {
+                let mut chunk = vec![0; CHUNK_SIZE];
+                let n = file.read(&mut chunk).await?;
+                if n == 0 {
+                    return Ok(None);
+                }
+                chunk.truncate(n);
+                Ok(Some((Bytes::from(chunk), file)))
+            });
+            Ok(Streamed {
+                stream: Box::pin(stream),
+                sha256: None,
+                size: Some(size),
+            })
+        })
+    }
+
+    fn save(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        buf: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let path = self.file_path(name, prefix, bucket);
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+
+        Box::pin(async move {
+            let path = self.resolve(path?).await?;
+            info!("saving permanent file in {}", path.display());
+            // create the file's shard instead of the bucket
+            let dir = path.parent().map(PathBuf::from).unwrap_or_default();
+            match fs::create_dir_all(dir).await {
+                Ok(()) => (),
+                // ignore error, as an error is also thrown when the directory already exists
+                // if the error is fatal, the write operation will also fail
+                Err(err) => warn!(
+                    "creation of folder to save file was not successful: {}",
+                    err
+                ),
+            };
+
+            write_atomic(&path, &buf).await?;
+            self.remove_flat(&name, &prefix, &bucket).await
+        })
+    }
+
+    fn delete(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<(), Error>> {
+        let path = self.file_path(name, prefix, bucket);
+        let flat = self.flat_path(name, prefix, bucket);
+
+        let name = name.to_owned();
+        let bucket = bucket.to_owned();
+
+        Box::pin(async move {
+            remove(&self.resolve(path?).await?).await?;
+            remove(&self.resolve(flat?).await?).await?;
+            info!("deleted {} from {}", name, bucket);
+
+            Ok(())
+        })
+    }
+
+    fn delete_many(
+        &self,
+        names: &[String],
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let prefix = prefix.to_owned();
+        let bucket = bucket.to_owned();
+
+        let names = names.iter().map(|name| self.delete(name, &prefix, &bucket));
+
+        futures::future::try_join_all(names).map_ok(|_| ()).boxed()
+    }
+
+    fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>> {
+        info!("saving temporary file in bucket '{}'", bucket);
+
+        let file_uuid = Uuid::new_v4().to_simple().to_string();
+        let path = self.file_path(&file_uuid, TMP, bucket);
+
+        Box::pin(async move {
+            let path = self.resolve(path?).await?;
+            let dir = path.parent().map(PathBuf::from).unwrap_or_default();
+            match fs::create_dir_all(dir).await {
+                Ok(()) => (),
+                // ignore error, as an error is also thrown when the directory already exists
+                // if the error is fatal, the write operation will also fail
+                Err(err) => error!(
+                    "creation of folder to save file was not successful: {}",
+                    err
+                ),
+            };
+
+            write_atomic(&path, &buf).await?;
+
+            Ok(file_uuid)
+        })
+    }
+
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let to = self.file_path(new_name, new_prefix, bucket);
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        let (new_name, new_prefix) = (new_name.to_owned(), new_prefix.to_owned());
+
+        Box::pin(async move {
+            let from = self.locate(&name, &prefix, &bucket).await?;
+            let to = self.resolve(to?).await?;
+            if let Some(dir) = to.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            let buf = fs::read(&from).await?;
+            write_atomic(&to, &buf).await?;
+            self.remove_flat(&new_name, &new_prefix, &bucket).await?;
+            info!("copied {} to {}", from.display(), to.display());
+            Ok(())
+        })
+    }
+
+    fn rename(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let to = self.file_path(new_name, new_prefix, bucket);
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        let (new_name, new_prefix) = (new_name.to_owned(), new_prefix.to_owned());
+
+        Box::pin(async move {
+            let from = self.locate(&name, &prefix, &bucket).await?;
+            let to = self.resolve(to?).await?;
+            if let Some(dir) = to.parent() {
+                fs::create_dir_all(dir).await?;
+            }
+            // rename(2) replaces the target atomically
+            fs::rename(&from, &to).await?;
+            self.remove_flat(&new_name, &new_prefix, &bucket).await?;
+            info!("renamed {} to {}", from.display(), to.display());
+            Ok(())
+        })
+    }
+
+    fn exists(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<bool, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+
+        Box::pin(async move {
+            let path = self.locate(&name, &prefix, &bucket).await?;
+            match fs::metadata(path).await {
+                Ok(metadata) => Ok(metadata.is_file()),
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(false),
+                Err(err) => Err(err.into()),
+            }
+        })
+    }
+
+    fn stat(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Stat, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+
+        Box::pin(async move {
+            let path = self.locate(&name, &prefix, &bucket).await?;
+            let metadata = fs::metadata(path).await?;
+            Ok(Stat {
+                size: metadata.len(),
+                modified: metadata.modified().ok().map(DateTime::<Utc>::from),
+                etag: None,
+            })
+        })
+    }
+
+    /// Lists both layouts.
+    fn list(&self, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Vec<String>, Error>> {
+        let dir = self.checked(Path::new(bucket).join(prefix));
+        // prefixes may contain slashes, the last segment becomes part of the file name
+        let flat = self.flat_path("", prefix, bucket);
+
+        Box::pin(async move {
+            let dir = self.resolve(dir?).await?;
+            let mut names = Vec::new();
+            for first in entries(&dir, false).await? {
+                for second in entries(&dir.join(&first), false).await? {
+                    for name in entries(&dir.join(&first).join(&second), true).await? {
+                        // nested prefixes have directories at this level
+                        if shard(&name) == (first.clone(), second.clone()) {
+                            names.push(name);
+                        }
+                    }
+                }
+            }
+
+            let flat = flat?;
+            let flat_dir = self
+                .resolve(flat.parent().map(PathBuf::from).unwrap_or_default())
+                .await?;
+            let file_prefix = flat
+                .file_name()
+                .map(|f| f.to_string_lossy().into_owned())
+                .unwrap_or_default();
+            names.extend(
+                entries(&flat_dir, true)
+                    .await?
+                    .iter()
+                    .filter_map(|f| f.strip_prefix(&file_prefix))
+                    .map(String::from),
+            );
+            names.sort();
+            names.dedup();
+            Ok(names)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::shard;
+    use super::FilesystemError;
+    use super::FilesystemStorage;
+    use super::CHUNK_SIZE;
+    use crate::storage::sweep_tmp;
+    use crate::storage::Storage;
+    use crate::storage::TMP;
+    use chrono::Duration;
+    use futures::TryStreamExt;
+    use std::io;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    /// A storage root of its own for a test, removed again when dropped.
+    struct TestRoot(PathBuf);
+
+    impl TestRoot {
+        fn new() -> Self {
+            let root = std::env::temp_dir().join(format!("fossil-{}", Uuid::new_v4().to_simple()));
+            std::fs::create_dir_all(&root).unwrap();
+            TestRoot(root)
+        }
+
+        fn storage(&self) -> FilesystemStorage {
+            FilesystemStorage {
+                path: Arc::new(self.0.clone()),
+            }
+        }
+
+        fn sharded(&self, bucket: &str, prefix: &str, name: &str) -> PathBuf {
+            let (first, second) = shard(name);
+            self.0
+                .join(bucket)
+                .join(prefix)
+                .join(first)
+                .join(second)
+                .join(name)
+        }
+    }
+
+    impl Drop for TestRoot {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_access() -> io::Result<()> {
+        const BUCKET: &str = "test_read_access";
+
+        let root = TestRoot::new();
+        let storage = root.storage();
+
+        // create test directory
+        match std::fs::create_dir(root.0.join(BUCKET)) {
+            Ok(()) => (),
+            Err(err) => match err.kind() {
+                // ignore the error
+                std::io::ErrorKind::AlreadyExists => (),
+                _ => panic!("Error occured while setting up test: {:?}", err),
+            },
+        };
+
+        // write file to read
+        std::fs::write(root.0.join(BUCKET).join("1337-test.txt"), b"hello world")?;
+
+        let result = storage.load("test.txt", "1337", BUCKET).await;
+
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap(), b"hello world".to_vec());
+
+        std::fs::remove_file(root.0.join(BUCKET).join("1337-test.txt"))?;
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_non_existant_file_returns_err() {
+        const BUCKET: &str = "non_existant_bucket";
+        let root = TestRoot::new();
+        let storage = root.storage();
+
+        let result = storage.load("dontusemyname.txt", "1337-pro", BUCKET).await;
+
+        assert!(result.is_err());
+        assert_eq!(
+            result
+                .unwrap_err()
+                .downcast_ref::<std::io::Error>()
+                .unwrap()
+                .kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_simple_tmp_write() {
+        const BUCKET: &str = "simple_tmp_write";
+
+        let root = TestRoot::new();
+        let storage = root.storage();
+
+        // write file to read
+        let result = storage.save_tmp(BUCKET, b"hello world".to_vec()).await;
+
+        assert!(result.is_ok());
+
+        // make sure file holds the respective content
+        assert_eq!(
+            std::fs::read(root.sharded(BUCKET, TMP, &result.unwrap())).unwrap(),
+            b"hello world"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete() {
+        const BUCKET: &str = "delete_bucket";
+
+        let root = TestRoot::new();
+        let storage = root.storage();
+
+        // create bucket directory
+        match std::fs::create_dir(root.0.join(BUCKET)) {
+            Ok(()) => (),
+            Err(err) => match err.kind() {
+                // ignore the error
+                std::io::ErrorKind::AlreadyExists => (),
+                _ => panic!("Error occured while setting up test: {:?}", err),
+            },
+        };
+
+        // write file to delete
+        std::fs::write(root.0.join(BUCKET).join("pre-hello.txt"), b"hello world").unwrap();
+
+        let delete_result = storage.delete("hello.txt", "pre", BUCKET).await;
+
+        eprintln!("{delete_result:?}");
+        // make sure that the file was successfully deleted
+        assert!(delete_result.is_ok());
+
+        let metadata_result = std::fs::metadata(root.0.join(BUCKET).join("pre-hello.txt"));
+
+        // make sure file does not exist anymore
+        assert!(metadata_result.is_err());
+        assert_eq!(
+            metadata_result.unwrap_err().kind(),
+            std::io::ErrorKind::NotFound
+        );
+    }
+
+    #[tokio::test]
+    async fn test_delete_many() {
+        const PREFIX: &str = "pre";
+        const BUCKET: &str = "delete_many_bucket";
+
+        let test_files = vec![
+            (String::from("hello.txt"), b"world"),
+            (String::from("world.txt"), b"hello"),
+        ];
+
+        let root = TestRoot::new();
+        let storage = root.storage();
+
+        // create bucket directory
+        match std::fs::create_dir(root.0.join(BUCKET)) {
+            Ok(()) => (),
+            Err(err) => match err.kind() {
+                // ignore the error
+                std::io::ErrorKind::AlreadyExists => (),
+                _ => panic!("Error occured while setting up test: {:?}", err),
+            },
+        };
+
+        // write files to delete
+        for (name, content) in test_files.clone() {
+            std::fs::write(
+                root.0.join(BUCKET).join(format!("{PREFIX}-{name}")),
+                content,
+            )
+            .unwrap();
+        }
+
+        let delete_result = storage
+            .delete_many(
+                &test_files
+                    .clone()
+                    .iter()
+                    .map(|(name, _content)| name.to_owned())
+                    .collect::<Vec<String>>()[..],
+                PREFIX,
+                BUCKET,
+            )
+            .await;
+
+        eprintln!("{delete_result:?}");
+        assert!(delete_result.is_ok());
+
+        // make sure all the files were deleted
+        for (name, _content) in test_files.clone() {
+            let metadata_result =
+                std::fs::metadata(root.0.join(BUCKET).join(format!("{PREFIX}-{name}")));
+
+            assert!(metadata_result.is_err());
+            assert_eq!(
+                metadata_result.unwrap_err().kind(),
+                std::io::ErrorKind::NotFound
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rename() {
+        const BUCKET: &str = "rename_bucket";
+
+        let root = TestRoot::new();
+        let storage = root.storage();
+
+        storage
+            .save("hello.txt", "pre", BUCKET, b"hello world".to_vec())
+            .await
+            .unwrap();
+
+        storage
+            .rename("hello.txt", "pre", "world.txt", "post", BUCKET)
+            .await
+            .unwrap();
+
+        // make sure the file moved
+        assert!(std::fs::metadata(root.sharded(BUCKET, "pre", "hello.txt")).is_err());
+        assert_eq!(
+            std::fs::read(root.sharded(BUCKET, "post", "world.txt")).unwrap(),
+            b"hello world"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_overwrite_with_shorter_content() {
+        const BUCKET: &str = "overwrite_bucket";
+
+        let root = TestRoot::new();
+        let storage = root.storage();
+
+        storage
+            .save("hello.txt", "pre", BUCKET, b"hello world".to_vec())
+            .await
+            .unwrap();
+        storage
+            .save("hello.txt", "pre", BUCKET, b"hi".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            std::fs::read(root.sharded(BUCKET, "pre", "hello.txt")).unwrap(),
+            b"hi"
+        );
+        // no temporary files are left behind
+        let shard_dir = root.sharded(BUCKET, "pre", "hello.txt");
+        assert!(std::fs::read_dir(shard_dir.parent().unwrap())
+            .unwrap()
+            .all(|entry| !entry
+                .unwrap()
+                .file_name()
+                .to_string_lossy()
+                .starts_with('.')));
+    }
+
+    #[tokio::test]
+    async fn test_reject_traversal() {
+        let root = TestRoot::new();
+        let storage = root.storage();
+
+        for (name, prefix, bucket) in &[
+            ("passwd", "../../etc", "bucket"),
+            ("passwd", "x", "../bucket"),
+            ("passwd", "a/../../..", "bucket"),
+            ("passwd", "x", "/etc"),
+        ] {
+            let err = storage.load(name, prefix, bucket).await.unwrap_err();
+            assert!(err.downcast_ref::<FilesystemError>().is_some());
+            assert!(storage
+                .save(name, prefix, bucket, b"doom".to_vec())
+                .await
+                .is_err());
+        }
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_reject_symlink_out_of_root() {
+        let tmp = TestRoot::new();
+        let root = tmp.0.join("root");
+        let outside = tmp.0.join("outside");
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, root.join("bucket")).unwrap();
+
+        let storage = FilesystemStorage {
+            path: Arc::new(root),
+        };
+        let err = storage
+            .save("hello.txt", "pre", "bucket", b"doom".to_vec())
+            .await
+            .unwrap_err();
+        assert!(err.downcast_ref::<FilesystemError>().is_some());
+        assert!(std::fs::metadata(outside.join("pre-hello.txt")).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_sweep_tmp() {
+        const BUCKET: &str = "sweep_bucket";
+
+        let root = TestRoot::new();
+        let storage = root.storage();
+
+        let name = storage.save_tmp(BUCKET, b"hello".to_vec()).await.unwrap();
+        storage
+            .save("hello.txt", "pre", BUCKET, b"world".to_vec())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            sweep_tmp(&storage, BUCKET, Duration::minutes(60))
+                .await
+                .unwrap(),
+            0
+        );
+        assert!(storage.exists(&name, TMP, BUCKET).await.unwrap());
+
+        // a negative ttl expires everything
+        assert_eq!(
+            sweep_tmp(&storage, BUCKET, Duration::minutes(-1))
+                .await
+                .unwrap(),
+            1
+        );
+        assert!(!storage.exists(&name, TMP, BUCKET).await.unwrap());
+        assert!(storage.exists("hello.txt", "pre", BUCKET).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_exists_stat_and_list() {
+        const BUCKET: &str = "list_bucket";
+
+        let root = TestRoot::new();
+        let storage = root.storage();
+
+        storage
+            .save("a.txt", "dir/pre", BUCKET, b"hello".to_vec())
+            .await
+            .unwrap();
+        storage
+            .save("b.txt", "dir/pre", BUCKET, b"hello world".to_vec())
+            .await
+            .unwrap();
+        storage
+            .save("c.txt", "dir/other", BUCKET, b"hello".to_vec())
+            .await
+            .unwrap();
+
+        assert!(storage.exists("a.txt", "dir/pre", BUCKET).await.unwrap());
+        assert!(!storage.exists("c.txt", "dir/pre", BUCKET).await.unwrap());
+        assert_eq!(
+            storage.stat("b.txt", "dir/pre", BUCKET).await.unwrap().size,
+            11
+        );
+
+        let mut names = storage.list("dir/pre", BUCKET).await.unwrap();
+        names.sort();
+        assert_eq!(names, vec!["a.txt", "b.txt"]);
+        assert!(storage.list("missing", BUCKET).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_load_stream_in_chunks() {
+        const BUCKET: &str = "stream_bucket";
+
+        let root = TestRoot::new();
+        let storage = root.storage();
+        let buf: Vec<u8> = (0..CHUNK_SIZE * 2 + 10).map(|i| i as u8).collect();
+        storage
+            .save("big.png", "raw", BUCKET, buf.clone())
+            .await
+            .unwrap();
+
+        let streamed = storage.load_stream("big.png", "raw", BUCKET).await.unwrap();
+        assert_eq!(streamed.size, Some(buf.len() as u64));
+        assert_eq!(streamed.sha256, None);
+        let chunks: Vec<_> = streamed.stream.try_collect().await.unwrap();
+        assert_eq!(chunks.len(), 3);
+        assert_eq!(chunks.concat(), buf);
+
+        assert!(storage
+            .load_stream("none.png", "raw", BUCKET)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_migrate_flat_layout() {
+        const BUCKET: &str = "migrate_bucket";
+
+        let root = TestRoot::new();
+        let storage = root.storage();
+        let dir = root.0.join(BUCKET);
+        std::fs::create_dir_all(dir.join("history")).unwrap();
+        let hash = "0123abcd_public.png";
+        std::fs::write(dir.join(format!("264-{}", hash)), b"264").unwrap();
+        std::fs::write(dir.join("history").join("40-index.json"), b"{}").unwrap();
+        storage
+            .save("new.png", "264", BUCKET, b"new".to_vec())
+            .await
+            .unwrap();
+
+        // both layouts are readable before migrating
+        assert_eq!(storage.load(hash, "264", BUCKET).await.unwrap(), b"264");
+        let mut names = storage.list("264", BUCKET).await.unwrap();
+        names.sort();
+        assert_eq!(names, vec![hash, "new.png"]);
+
+        assert_eq!(storage.migrate_flat(BUCKET).await.unwrap(), 2);
+        assert!(!dir.join(format!("264-{}", hash)).exists());
+        assert_eq!(
+            std::fs::read(root.sharded(BUCKET, "264", hash)).unwrap(),
+            b"264"
+        );
+        assert!(root
+            .sharded(BUCKET, "264", hash)
+            .starts_with(dir.join("264").join("01").join("23")));
+        assert_eq!(
+            storage
+                .load("index.json", "history/40", BUCKET)
+                .await
+                .unwrap(),
+            b"{}"
+        );
+        assert_eq!(storage.list("264", BUCKET).await.unwrap().len(), 2);
+
+        assert_eq!(storage.migrate_flat(BUCKET).await.unwrap(), 0);
+    }
+}
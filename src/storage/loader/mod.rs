@@ -1,13 +1,77 @@
-#[cfg(feature = "local-fs")]
-pub mod filesystem;
-
-#[cfg(not(feature = "local-fs"))]
-pub mod s3;
-
+use crate::storage::Hashed;
+use crate::storage::Storage;
+use crate::storage::StorageError;
+use crate::storage::Streamed;
 use failure::Error;
 use futures::future::BoxFuture;
 
 pub trait Loader: Sync + Send + Sized {
     fn load(&self, name: &str, prefix: &str, bucket: &str)
         -> BoxFuture<'_, Result<Vec<u8>, Error>>;
+    fn load_hashed(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Hashed, Error>> {
+        let load = self.load(name, prefix, bucket);
+        Box::pin(async move { Ok(Hashed::new(load.await?)) })
+    }
+    fn load_stream(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Streamed, Error>> {
+        let load = self.load_hashed(name, prefix, bucket);
+        Box::pin(async move { Ok(load.await?.into()) })
+    }
+    fn load_version(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        version_id: Option<&str>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        match version_id {
+            None => self.load(name, prefix, bucket),
+            Some(_) => Box::pin(async { Err(StorageError::VersionsUnsupported.into()) }),
+        }
+    }
+}
+
+impl<T: Storage> Loader for T {
+    fn load(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        Storage::load(self, name, prefix, bucket)
+    }
+    fn load_hashed(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Hashed, Error>> {
+        Storage::load_hashed(self, name, prefix, bucket)
+    }
+    fn load_stream(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Streamed, Error>> {
+        Storage::load_stream(self, name, prefix, bucket)
+    }
+    fn load_version(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        version_id: Option<&str>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        Storage::load_version(self, name, prefix, bucket, version_id)
+    }
 }
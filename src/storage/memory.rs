@@ -0,0 +1,344 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
+use super::Stat;
+use super::Storage;
+use super::StorageError;
+use super::Version;
+use super::TMP;
+use chrono::DateTime;
+use chrono::Utc;
+use failure::Error;
+use futures::future::BoxFuture;
+use log::info;
+use std::collections::BTreeMap;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use uuid::Uuid;
+
+#[derive(Debug, Fail)]
+pub enum MemoryError {
+    #[fail(display = "no such object")]
+    NotFound,
+    #[fail(display = "injected failure for {}", _0)]
+    Injected(String),
+}
+
+struct Object {
+    buf: Vec<u8>,
+    modified: DateTime<Utc>,
+}
+
+impl Object {
+    fn new(buf: Vec<u8>) -> Self {
+        Object {
+            buf,
+            modified: Utc::now(),
+        }
+    }
+}
+
+/// Keeps all objects in memory. Every write (`save`, `save_tmp` and the target
+/// of `copy`) counts as a save for failure injection.
+#[derive(Default)]
+pub struct MemoryStorage {
+    files: Mutex<BTreeMap<String, Object>>,
+    /// Overwritten and deleted objects, oldest first, if versioned.
+    previous: Mutex<BTreeMap<String, Vec<Object>>>,
+    versioned: bool,
+    saves: AtomicUsize,
+    fail_nth_save: Option<usize>,
+    fail_prefix: Option<String>,
+    fail_load_prefix: Option<String>,
+}
+
+impl MemoryStorage {
+    /// Lets the `n`th save (counting from 1) fail.
+    pub fn fail_nth_save(mut self, n: usize) -> Self {
+        self.fail_nth_save = Some(n);
+        self
+    }
+
+    /// Lets every save with `prefix` fail.
+    pub fn fail_prefix(mut self, prefix: &str) -> Self {
+        self.fail_prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Lets every load with `prefix` fail.
+    #[cfg(test)]
+    pub fn fail_load_prefix(mut self, prefix: &str) -> Self {
+        self.fail_load_prefix = Some(prefix.to_owned());
+        self
+    }
+
+    /// Keeps overwritten and deleted objects as versions.
+    #[cfg(test)]
+    pub fn versioned(mut self) -> Self {
+        self.versioned = true;
+        self
+    }
+
+    /// Stores an object without counting it as a save.
+    #[cfg(test)]
+    pub fn insert(&self, name: &str, prefix: &str, bucket: &str, buf: Vec<u8>) {
+        self.files
+            .lock()
+            .unwrap()
+            .insert(Self::key(name, prefix, bucket), Object::new(buf));
+    }
+
+    #[cfg(test)]
+    pub fn is_empty(&self) -> bool {
+        self.files.lock().unwrap().is_empty()
+    }
+
+    #[cfg(test)]
+    pub fn saves(&self) -> usize {
+        self.saves.load(Ordering::SeqCst)
+    }
+
+    fn key(name: &str, prefix: &str, bucket: &str) -> String {
+        format!("{}/{}/{}", bucket, prefix, name)
+    }
+
+    fn write(&self, name: &str, prefix: &str, bucket: &str, buf: Vec<u8>) -> Result<(), Error> {
+        let n = self.saves.fetch_add(1, Ordering::SeqCst) + 1;
+        if self.fail_nth_save == Some(n) || self.fail_prefix.as_deref() == Some(prefix) {
+            return Err(MemoryError::Injected(format!("{}/{}", prefix, name)).into());
+        }
+        let key = Self::key(name, prefix, bucket);
+        let old = self
+            .files
+            .lock()
+            .unwrap()
+            .insert(key.clone(), Object::new(buf));
+        self.keep(key, old);
+        Ok(())
+    }
+
+    fn keep(&self, key: String, old: Option<Object>) {
+        if let (true, Some(old)) = (self.versioned, old) {
+            self.previous
+                .lock()
+                .unwrap()
+                .entry(key)
+                .or_default()
+                .push(old);
+        }
+    }
+}
+
+impl Storage for MemoryStorage {
+    fn load(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        if self.fail_load_prefix.as_deref() == Some(prefix) {
+            let e = MemoryError::Injected(format!("{}/{}", prefix, name)).into();
+            return Box::pin(async move { Err(e) });
+        }
+        let ret = self
+            .files
+            .lock()
+            .unwrap()
+            .get(&Self::key(name, prefix, bucket))
+            .map(|object| object.buf.clone())
+            .ok_or_else(|| MemoryError::NotFound.into());
+        Box::pin(async move { ret })
+    }
+    fn save(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        buf: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let ret = self.write(name, prefix, bucket, buf);
+        Box::pin(async move { ret })
+    }
+    /// Versions are ids into the kept objects, the current one comes last.
+    fn load_version(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        version_id: Option<&str>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        let version_id = match version_id {
+            None => return self.load(name, prefix, bucket),
+            Some(_) if !self.versioned => {
+                return Box::pin(async { Err(StorageError::VersionsUnsupported.into()) })
+            }
+            Some(version_id) => version_id,
+        };
+        let key = Self::key(name, prefix, bucket);
+        let previous = self.previous.lock().unwrap();
+        let kept = previous.get(&key).map(Vec::as_slice).unwrap_or_default();
+        let ret = match version_id.parse::<usize>() {
+            Ok(i) if i < kept.len() => Ok(kept[i].buf.clone()),
+            Ok(i) if i == kept.len() => self
+                .files
+                .lock()
+                .unwrap()
+                .get(&key)
+                .map(|object| object.buf.clone())
+                .ok_or_else(|| MemoryError::NotFound.into()),
+            _ => Err(MemoryError::NotFound.into()),
+        };
+        Box::pin(async move { ret })
+    }
+    fn delete(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<(), Error>> {
+        let key = Self::key(name, prefix, bucket);
+        let old = self.files.lock().unwrap().remove(&key);
+        self.keep(key, old);
+        Box::pin(async { Ok(()) })
+    }
+    fn delete_many(
+        &self,
+        names: &[String],
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        for name in names {
+            let key = Self::key(name, prefix, bucket);
+            let old = self.files.lock().unwrap().remove(&key);
+            self.keep(key, old);
+        }
+        Box::pin(async { Ok(()) })
+    }
+    fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>> {
+        let name = Uuid::new_v4().to_simple().to_string();
+        let ret = self.write(&name, TMP, bucket, buf).map(|_| {
+            info!("created tmp file {} in memory", name);
+            name
+        });
+        Box::pin(async move { ret })
+    }
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let buf = self
+            .files
+            .lock()
+            .unwrap()
+            .get(&Self::key(name, prefix, bucket))
+            .map(|object| object.buf.clone());
+        let ret = match buf {
+            Some(buf) => self.write(new_name, new_prefix, bucket, buf),
+            None => Err(MemoryError::NotFound.into()),
+        };
+        Box::pin(async move { ret })
+    }
+    fn exists(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<bool, Error>> {
+        let ret = self
+            .files
+            .lock()
+            .unwrap()
+            .contains_key(&Self::key(name, prefix, bucket));
+        Box::pin(async move { Ok(ret) })
+    }
+    fn stat(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Stat, Error>> {
+        let ret = self
+            .files
+            .lock()
+            .unwrap()
+            .get(&Self::key(name, prefix, bucket))
+            .map(|object| Stat {
+                size: object.buf.len() as u64,
+                modified: Some(object.modified),
+                etag: None,
+            })
+            .ok_or_else(|| MemoryError::NotFound.into());
+        Box::pin(async move { ret })
+    }
+    fn list(&self, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Vec<String>, Error>> {
+        let key_prefix = format!("{}/{}/", bucket, prefix);
+        let names = self
+            .files
+            .lock()
+            .unwrap()
+            .range(key_prefix.clone()..)
+            .map(|(key, _)| key)
+            .take_while(|key| key.starts_with(&key_prefix))
+            .map(|key| key[key_prefix.len()..].to_owned())
+            .collect();
+        Box::pin(async move { Ok(names) })
+    }
+    fn versions(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<Version>, Error>> {
+        if !self.versioned {
+            return Box::pin(async { Err(StorageError::VersionsUnsupported.into()) });
+        }
+        let key = Self::key(name, prefix, bucket);
+        let version = |i: usize, object: &Object, latest| Version {
+            version_id: i.to_string(),
+            modified: Some(object.modified.timestamp()),
+            size: object.buf.len() as u64,
+            latest,
+        };
+        let mut versions: Vec<_> = self
+            .previous
+            .lock()
+            .unwrap()
+            .get(&key)
+            .map(|kept| {
+                kept.iter()
+                    .enumerate()
+                    .map(|(i, object)| version(i, object, false))
+                    .collect()
+            })
+            .unwrap_or_default();
+        if let Some(object) = self.files.lock().unwrap().get(&key) {
+            versions.push(version(versions.len(), object, true));
+        }
+        versions.reverse();
+        Box::pin(async move { Ok(versions) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_fail_nth_save() -> Result<(), Error> {
+        let storage = MemoryStorage::default().fail_nth_save(2);
+        storage.save("a", "pre", "bucket", vec![1]).await?;
+        assert!(storage.save("b", "pre", "bucket", vec![2]).await.is_err());
+        storage.save("c", "pre", "bucket", vec![3]).await?;
+        assert!(!storage.exists("b", "pre", "bucket").await?);
+        assert_eq!(storage.list("pre", "bucket").await?, vec!["a", "c"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fail_prefix() -> Result<(), Error> {
+        let storage = MemoryStorage::default().fail_prefix("broken");
+        storage.save("a", "pre", "bucket", vec![1]).await?;
+        assert!(storage
+            .save("a", "broken", "bucket", vec![1])
+            .await
+            .is_err());
+        assert!(storage
+            .copy("a", "pre", "a", "broken", "bucket")
+            .await
+            .is_err());
+        assert!(storage.load("a", "pre", "other").await.is_err());
+        Ok(())
+    }
+}
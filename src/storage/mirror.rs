@@ -0,0 +1,430 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
+use super::Hashed;
+use super::Stat;
+use super::Storage;
+use super::Streamed;
+use super::Version;
+use super::TMP;
+use crate::settings::MismatchPolicy;
+use failure::Error;
+use futures::future::BoxFuture;
+use log::info;
+use log::warn;
+use std::collections::BTreeSet;
+use std::sync::Mutex;
+
+#[derive(Debug, Fail)]
+pub enum MirrorError {
+    #[fail(display = "secondary store out of sync for {}: {}", _0, _1)]
+    Mismatch(String, String),
+}
+
+/// An object the secondary store has to catch up on.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Repair {
+    pub name: String,
+    pub prefix: String,
+    pub bucket: String,
+}
+
+impl Repair {
+    fn new(name: &str, prefix: &str, bucket: &str) -> Self {
+        Repair {
+            name: name.to_owned(),
+            prefix: prefix.to_owned(),
+            bucket: bucket.to_owned(),
+        }
+    }
+}
+
+/// Writes go to `primary` first and then to `secondary`. Reads are served by
+/// `primary` and fail over to `secondary`. Versions only come from `primary`.
+pub struct MirroredStorage<P: Storage, S: Storage> {
+    primary: P,
+    secondary: S,
+    on_mismatch: MismatchPolicy,
+    repairs: Mutex<BTreeSet<Repair>>,
+}
+
+/// Awaits `primary` and calls `secondary` if that fails. Returns the error of
+/// `primary` if both fail.
+async fn failover<'a, T>(
+    what: String,
+    primary: BoxFuture<'a, Result<T, Error>>,
+    secondary: impl FnOnce() -> BoxFuture<'a, Result<T, Error>>,
+) -> Result<T, Error> {
+    match primary.await {
+        Ok(t) => Ok(t),
+        Err(e) => {
+            warn!("primary store failed {}, trying secondary: {}", what, e);
+            secondary().await.map_err(|_| e)
+        }
+    }
+}
+
+impl<P: Storage, S: Storage> MirroredStorage<P, S> {
+    pub fn new(primary: P, secondary: S, on_mismatch: MismatchPolicy) -> Self {
+        MirroredStorage {
+            primary,
+            secondary,
+            on_mismatch,
+            repairs: Default::default(),
+        }
+    }
+
+    pub fn primary(&self) -> &P {
+        &self.primary
+    }
+
+    pub fn secondary(&self) -> &S {
+        &self.secondary
+    }
+
+    #[cfg(test)]
+    pub fn pending(&self) -> Vec<Repair> {
+        self.repairs.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Writes to `primary` and, if that worked, to `secondary`. A failure of
+    /// the latter leaves `keys` out of sync.
+    async fn mirror<'a>(
+        &'a self,
+        keys: Vec<Repair>,
+        primary: BoxFuture<'a, Result<(), Error>>,
+        secondary: impl FnOnce() -> BoxFuture<'a, Result<(), Error>>,
+    ) -> Result<(), Error> {
+        primary.await?;
+        match secondary().await {
+            Ok(()) => Ok(()),
+            Err(e) => self.mismatch(keys, e),
+        }
+    }
+
+    fn mismatch(&self, keys: Vec<Repair>, e: Error) -> Result<(), Error> {
+        let names = keys
+            .iter()
+            .map(|r| format!("{}/{}", r.prefix, r.name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        match self.on_mismatch {
+            MismatchPolicy::Error => Err(MirrorError::Mismatch(names, e.to_string()).into()),
+            MismatchPolicy::Queue => {
+                warn!("queueing repair of {}: {}", names, e);
+                self.repairs.lock().unwrap().extend(keys);
+                Ok(())
+            }
+        }
+    }
+
+    /// Copies every queued object from `primary` to `secondary`, or deletes
+    /// it there when it is gone from `primary`. Failed repairs stay queued.
+    /// Returns the number of repaired objects.
+    pub async fn repair(&self) -> usize {
+        let repairs = std::mem::take(&mut *self.repairs.lock().unwrap());
+        let mut repaired = 0;
+        for r in repairs {
+            match self.repair_one(&r).await {
+                Ok(()) => repaired += 1,
+                Err(e) => {
+                    warn!("unable to repair {}/{}: {}", r.prefix, r.name, e);
+                    self.repairs.lock().unwrap().insert(r);
+                }
+            }
+        }
+        if repaired > 0 {
+            info!("repaired {} objects in the secondary store", repaired);
+        }
+        repaired
+    }
+
+    async fn repair_one(&self, r: &Repair) -> Result<(), Error> {
+        if self.primary.exists(&r.name, &r.prefix, &r.bucket).await? {
+            let buf = self.primary.load(&r.name, &r.prefix, &r.bucket).await?;
+            self.secondary
+                .save(&r.name, &r.prefix, &r.bucket, buf)
+                .await
+        } else {
+            self.secondary.delete(&r.name, &r.prefix, &r.bucket).await
+        }
+    }
+}
+
+impl<P: Storage, S: Storage> Storage for MirroredStorage<P, S> {
+    fn load(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("loading {}/{}", prefix, name);
+            let primary = self.primary.load(&name, &prefix, &bucket);
+            failover(what, primary, || {
+                self.secondary.load(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn load_hashed(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Hashed, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("loading {}/{}", prefix, name);
+            let primary = self.primary.load_hashed(&name, &prefix, &bucket);
+            failover(what, primary, || {
+                self.secondary.load_hashed(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    /// Only opening the stream fails over.
+    fn load_stream(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Streamed, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("loading {}/{}", prefix, name);
+            let primary = self.primary.load_stream(&name, &prefix, &bucket);
+            failover(what, primary, || {
+                self.secondary.load_stream(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    /// Version ids belong to `primary`, only the current version fails over.
+    fn load_version(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        version_id: Option<&str>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        match version_id {
+            None => self.load(name, prefix, bucket),
+            Some(_) => self.primary.load_version(name, prefix, bucket, version_id),
+        }
+    }
+    fn save(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        buf: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let keys = vec![Repair::new(&name, &prefix, &bucket)];
+            let primary = self.primary.save(&name, &prefix, &bucket, buf.clone());
+            self.mirror(keys, primary, || {
+                self.secondary.save(&name, &prefix, &bucket, buf)
+            })
+            .await
+        })
+    }
+    fn delete(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let keys = vec![Repair::new(&name, &prefix, &bucket)];
+            let primary = self.primary.delete(&name, &prefix, &bucket);
+            self.mirror(keys, primary, || {
+                self.secondary.delete(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn delete_many(
+        &self,
+        names: &[String],
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (names, prefix, bucket) = (names.to_vec(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let keys = names
+                .iter()
+                .map(|name| Repair::new(name, &prefix, &bucket))
+                .collect();
+            let primary = self.primary.delete_many(&names, &prefix, &bucket);
+            self.mirror(keys, primary, || {
+                self.secondary.delete_many(&names, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    /// The name is picked by `primary` and reused for `secondary`.
+    fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>> {
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            let name = self.primary.save_tmp(&bucket, buf.clone()).await?;
+            let keys = vec![Repair::new(&name, TMP, &bucket)];
+            match self.secondary.save(&name, TMP, &bucket, buf).await {
+                Ok(()) => (),
+                Err(e) => self.mismatch(keys, e)?,
+            }
+            Ok(name)
+        })
+    }
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        let (new_name, new_prefix) = (new_name.to_owned(), new_prefix.to_owned());
+        Box::pin(async move {
+            let keys = vec![Repair::new(&new_name, &new_prefix, &bucket)];
+            let primary = self
+                .primary
+                .copy(&name, &prefix, &new_name, &new_prefix, &bucket);
+            self.mirror(keys, primary, || {
+                self.secondary
+                    .copy(&name, &prefix, &new_name, &new_prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn rename(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        let (new_name, new_prefix) = (new_name.to_owned(), new_prefix.to_owned());
+        Box::pin(async move {
+            let keys = vec![
+                Repair::new(&name, &prefix, &bucket),
+                Repair::new(&new_name, &new_prefix, &bucket),
+            ];
+            let primary = self
+                .primary
+                .rename(&name, &prefix, &new_name, &new_prefix, &bucket);
+            self.mirror(keys, primary, || {
+                self.secondary
+                    .rename(&name, &prefix, &new_name, &new_prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn exists(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<bool, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("checking {}/{}", prefix, name);
+            let primary = self.primary.exists(&name, &prefix, &bucket);
+            failover(what, primary, || {
+                self.secondary.exists(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn stat(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Stat, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("checking {}/{}", prefix, name);
+            let primary = self.primary.stat(&name, &prefix, &bucket);
+            failover(what, primary, || {
+                self.secondary.stat(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn list(&self, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Vec<String>, Error>> {
+        let (prefix, bucket) = (prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("listing {}", prefix);
+            let primary = self.primary.list(&prefix, &bucket);
+            failover(what, primary, || self.secondary.list(&prefix, &bucket)).await
+        })
+    }
+    fn versions(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<Version>, Error>> {
+        self.primary.versions(name, prefix, bucket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+
+    fn mirror(
+        secondary: MemoryStorage,
+        on_mismatch: MismatchPolicy,
+    ) -> MirroredStorage<MemoryStorage, MemoryStorage> {
+        MirroredStorage::new(MemoryStorage::default(), secondary, on_mismatch)
+    }
+
+    #[tokio::test]
+    async fn test_writes_reach_both_stores() -> Result<(), Error> {
+        let storage = mirror(MemoryStorage::default(), MismatchPolicy::Error);
+        Storage::save(&storage, "a", "p", "b", vec![1]).await?;
+        let tmp = Storage::save_tmp(&storage, "b", vec![2]).await?;
+        assert_eq!(storage.primary().load("a", "p", "b").await?, vec![1]);
+        assert_eq!(storage.secondary().load("a", "p", "b").await?, vec![1]);
+        assert_eq!(storage.secondary().load(&tmp, TMP, "b").await?, vec![2]);
+        Storage::rename(&storage, "a", "p", "c", "q", "b").await?;
+        assert!(!storage.secondary().exists("a", "p", "b").await?);
+        assert_eq!(storage.secondary().load("c", "q", "b").await?, vec![1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mismatch_is_an_error() -> Result<(), Error> {
+        let storage = mirror(
+            MemoryStorage::default().fail_prefix("p"),
+            MismatchPolicy::Error,
+        );
+        let e = Storage::save(&storage, "a", "p", "b", vec![1])
+            .await
+            .unwrap_err();
+        assert!(e.downcast_ref::<MirrorError>().is_some());
+        assert!(storage.pending().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_mismatch_is_queued_and_repaired() -> Result<(), Error> {
+        let storage = mirror(
+            MemoryStorage::default().fail_nth_save(1),
+            MismatchPolicy::Queue,
+        );
+        Storage::save(&storage, "a", "p", "b", vec![1]).await?;
+        assert_eq!(storage.pending(), vec![Repair::new("a", "p", "b")]);
+        assert!(!storage.secondary().exists("a", "p", "b").await?);
+
+        assert_eq!(storage.repair().await, 1);
+        assert!(storage.pending().is_empty());
+        assert_eq!(storage.secondary().load("a", "p", "b").await?, vec![1]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reads_fail_over() -> Result<(), Error> {
+        let storage = mirror(MemoryStorage::default(), MismatchPolicy::Error);
+        storage.secondary().insert("a", "p", "b", vec![1]);
+        assert_eq!(Storage::load(&storage, "a", "p", "b").await?, vec![1]);
+        assert!(Storage::load(&storage, "x", "p", "b").await.is_err());
+        Ok(())
+    }
+}
@@ -1,3 +1,239 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
+pub mod backend;
+pub mod cache;
+pub mod dedup;
+pub mod encrypted;
+pub mod filesystem;
 pub mod loader;
+pub mod memory;
+pub mod mirror;
 pub mod name;
+pub mod retry;
+pub mod s3;
+pub mod s3_api;
 pub mod saver;
+
+use actix_web::web::Bytes;
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use failure::Error;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use log::info;
+use log::warn;
+use serde::Serialize;
+use sha2::Digest;
+
+/// Prefix of uploaded intermediate pictures.
+pub const TMP: &str = "tmp";
+
+#[derive(Debug, Fail)]
+pub enum StorageError {
+    #[fail(display = "object versions are not supported by this backend")]
+    VersionsUnsupported,
+}
+
+/// A stored version of an object.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Version {
+    pub version_id: String,
+    /// Unix timestamp.
+    pub modified: Option<i64>,
+    pub size: u64,
+    pub latest: bool,
+}
+
+/// Metadata of a stored object.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Stat {
+    pub size: u64,
+    pub modified: Option<DateTime<Utc>>,
+    pub etag: Option<String>,
+}
+
+/// Object contents along with their SHA-256, hex encoded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hashed {
+    pub buf: Vec<u8>,
+    pub sha256: String,
+}
+
+impl Hashed {
+    pub fn new(buf: Vec<u8>) -> Self {
+        Hashed {
+            sha256: sha256_hex(&buf),
+            buf,
+        }
+    }
+}
+
+/// Object contents as they arrive.
+pub type ByteStream = BoxStream<'static, Result<Bytes, Error>>;
+
+/// A streamed object. The hash is only known for backends storing it
+/// alongside the object.
+pub struct Streamed {
+    pub stream: ByteStream,
+    pub sha256: Option<String>,
+    pub size: Option<u64>,
+}
+
+impl From<Hashed> for Streamed {
+    fn from(Hashed { buf, sha256 }: Hashed) -> Self {
+        Streamed {
+            size: Some(buf.len() as u64),
+            sha256: Some(sha256),
+            stream: Box::pin(futures::stream::once(futures::future::ok(Bytes::from(buf)))),
+        }
+    }
+}
+
+pub fn sha256_hex(buf: &[u8]) -> String {
+    format!("{:x}", sha2::Sha256::digest(buf))
+}
+
+/// Whether `e` reports a missing object, for any backend.
+pub fn is_not_found(e: &Error) -> bool {
+    if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        return e.kind() == std::io::ErrorKind::NotFound;
+    }
+    if let Some(memory::MemoryError::NotFound) = e.downcast_ref::<memory::MemoryError>() {
+        return true;
+    }
+    s3::is_not_found(e)
+}
+
+/// Everything a storage backend provides. `Loader` and `Saver` are implemented
+/// for every `Storage`.
+pub trait Storage: Sync + Send + Sized {
+    fn load(&self, name: &str, prefix: &str, bucket: &str)
+        -> BoxFuture<'_, Result<Vec<u8>, Error>>;
+    /// Loads an object along with its hash. Backends storing the hash
+    /// alongside the object return that one.
+    fn load_hashed(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Hashed, Error>> {
+        let load = self.load(name, prefix, bucket);
+        Box::pin(async move { Ok(Hashed::new(load.await?)) })
+    }
+    /// Loads a previous version of an object, the current one for `None`.
+    fn load_version(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        version_id: Option<&str>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        match version_id {
+            None => self.load(name, prefix, bucket),
+            Some(_) => Box::pin(async { Err(StorageError::VersionsUnsupported.into()) }),
+        }
+    }
+    /// Loads an object without buffering it. Backends that can't stream hand
+    /// out what `load_hashed` returns in a single chunk.
+    fn load_stream(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Streamed, Error>> {
+        let load = self.load_hashed(name, prefix, bucket);
+        Box::pin(async move { Ok(load.await?.into()) })
+    }
+    fn save(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        buf: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>>;
+    fn delete(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<(), Error>>;
+    fn delete_many(
+        &self,
+        names: &[String],
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>>;
+    fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>>;
+    /// Copies an object within `bucket` without passing it through the service.
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>>;
+    /// Moves an object within `bucket`. Backends without a native rename copy
+    /// and delete.
+    fn rename(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let name = name.to_owned();
+        let prefix = prefix.to_owned();
+        let new_name = new_name.to_owned();
+        let new_prefix = new_prefix.to_owned();
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            self.copy(&name, &prefix, &new_name, &new_prefix, &bucket)
+                .await?;
+            self.delete(&name, &prefix, &bucket).await
+        })
+    }
+    fn exists(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<bool, Error>>;
+    fn stat(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Stat, Error>>;
+    /// Returns the names of all objects stored under `prefix`.
+    fn list(&self, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Vec<String>, Error>>;
+    /// Lists the stored versions of an object, newest first.
+    fn versions(
+        &self,
+        _name: &str,
+        _prefix: &str,
+        _bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<Version>, Error>> {
+        Box::pin(async { Err(StorageError::VersionsUnsupported.into()) })
+    }
+}
+
+/// Deletes tmp files older than `ttl`. Needed for backends without lifecycle
+/// rules. Returns the number of deleted files.
+pub async fn sweep_tmp(
+    storage: &impl Storage,
+    bucket: &str,
+    ttl: Duration,
+) -> Result<usize, Error> {
+    let cutoff = Utc::now() - ttl;
+    let mut swept = 0;
+    for name in storage.list(TMP, bucket).await? {
+        match storage.stat(&name, TMP, bucket).await {
+            Ok(Stat {
+                modified: Some(modified),
+                ..
+            }) if modified < cutoff => match storage.delete(&name, TMP, bucket).await {
+                Ok(()) => swept += 1,
+                // retried by the next sweep, the others still get swept
+                Err(e) => warn!("unable to delete tmp file {}: {}", name, e),
+            },
+            Ok(_) => (),
+            // might have been saved in the meantime
+            Err(e) => warn!("unable to stat tmp file {}: {}", name, e),
+        }
+    }
+    if swept > 0 {
+        info!("swept {} expired tmp files from {}", swept, bucket);
+    }
+    Ok(swept)
+}
@@ -6,7 +6,11 @@
 use chrono::Utc;
 use cis_profile::schema::Display;
 use failure::Error;
+use hmac::Hmac;
+use hmac::Mac;
+use hmac::NewMac;
 use sha2::Digest;
+use sha2::Sha256;
 use std::convert::TryInto;
 use std::fmt;
 
@@ -20,10 +24,77 @@ pub enum NameError {
     InvalidName,
 }
 
-pub fn uuid_hash(uuid: &str) -> String {
-    format!("{:x}", sha2::Sha256::digest(uuid.as_bytes()))
+/// All display levels an avatar can be stored under.
+pub const DISPLAY_LEVELS: [Display; 6] = [
+    Display::Public,
+    Display::Authenticated,
+    Display::Vouched,
+    Display::Ndaed,
+    Display::Staff,
+    Display::Private,
+];
+
+/// How the uuid hash in a name was computed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HashVersion {
+    /// Plain SHA-256 of the uuid.
+    V1,
+    /// HMAC-SHA256 of the uuid keyed with `uuid_hash_secret`.
+    V2,
+}
+
+impl HashVersion {
+    /// The version new names are created with, keyed once there is a secret.
+    pub fn current(secret: Option<&str>) -> Self {
+        match secret {
+            Some(_) => HashVersion::V2,
+            None => HashVersion::V1,
+        }
+    }
+}
+
+pub fn sha256_uuid_hash(uuid: &str) -> String {
+    format!("{:x}", Sha256::digest(uuid.as_bytes()))
+}
+
+pub fn keyed_uuid_hash(key: &[u8], uuid: &str) -> String {
+    // HMAC takes keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("any key length");
+    mac.update(uuid.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// The hash of `uuid` in names of `version`, `None` without a secret for
+/// `HashVersion::V2`.
+pub fn versioned_uuid_hash(
+    version: HashVersion,
+    uuid: &str,
+    secret: Option<&str>,
+) -> Option<String> {
+    match version {
+        HashVersion::V1 => Some(sha256_uuid_hash(uuid)),
+        HashVersion::V2 => secret.map(|secret| keyed_uuid_hash(secret.as_bytes(), uuid)),
+    }
+}
+
+/// The hash of `uuid` in new names.
+pub fn uuid_hash(uuid: &str, secret: Option<&str>) -> String {
+    match secret {
+        Some(secret) => keyed_uuid_hash(secret.as_bytes(), uuid),
+        None => sha256_uuid_hash(uuid),
+    }
 }
 
+/// The hashes `uuid`'s objects may be stored under, the current one first.
+/// Names created before the secret was set keep their plain hash.
+pub fn uuid_hashes(uuid: &str, secret: Option<&str>) -> Vec<(HashVersion, String)> {
+    [HashVersion::V2, HashVersion::V1]
+        .iter()
+        .filter_map(|version| Some((*version, versioned_uuid_hash(*version, uuid, secret)?)))
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct InternalFileName {
     pub uuid_hash: String,
     pub display: Display,
@@ -42,79 +113,198 @@ impl fmt::Display for InternalFileName {
 }
 
 impl InternalFileName {
-    pub fn from_uuid_and_display(uuid: &str, display: &Display) -> Self {
+    pub fn from_uuid_and_display(uuid: &str, display: &Display, secret: Option<&str>) -> Self {
         InternalFileName {
-            uuid_hash: uuid_hash(uuid),
+            uuid_hash: uuid_hash(uuid, secret),
             display: display.to_owned(),
         }
     }
+    /// Parses a stored name, `None` for anything not named after a display
+    /// level like history versions or tmp files.
+    pub fn from_name(name: &str) -> Option<Self> {
+        let stem = name.strip_suffix(FILE_ENDING)?.strip_suffix('.')?;
+        let (uuid_hash, display) = stem.rsplit_once('_')?;
+        Some(InternalFileName {
+            uuid_hash: uuid_hash.to_owned(),
+            display: display.try_into().ok()?,
+        })
+    }
+}
+
+/// Name of an archived avatar version, keyed by the timestamp of the
+/// `ExternalFileName` it was served under.
+pub struct HistoryFileName {
+    pub uuid_hash: String,
+    pub ts: i64,
+}
+
+impl fmt::Display for HistoryFileName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}_{}.{}", &self.uuid_hash, self.ts, FILE_ENDING)
+    }
+}
+
+/// Format of the served picture.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum ImageFormat {
+    #[default]
+    Png,
+    Jpeg,
+    Webp,
+}
+
+impl ImageFormat {
+    pub fn extension(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => FILE_ENDING,
+            ImageFormat::Jpeg => "jpg",
+            ImageFormat::Webp => "webp",
+        }
+    }
+    pub fn mime_type(&self) -> &'static str {
+        match self {
+            ImageFormat::Png => "image/png",
+            ImageFormat::Jpeg => "image/jpeg",
+            ImageFormat::Webp => "image/webp",
+        }
+    }
+    fn from_extension(extension: &str) -> Result<Self, NameError> {
+        match extension {
+            "png" => Ok(ImageFormat::Png),
+            "jpg" => Ok(ImageFormat::Jpeg),
+            "webp" => Ok(ImageFormat::Webp),
+            _ => Err(NameError::InvalidName),
+        }
+    }
 }
 
+/// The name a picture is served under. Encoded versions:
+///
+/// 1. `{hash}#{display}#{ts}`
+/// 2. `2#{hash}#{display}#{ts}` with a `HashVersion::V2` hash
+/// 3. `3#{hash version}#{hash}#{display}#{ts}#{format}#{flags}` with the
+///    image format's extension and hex encoded flags
+///
+/// All of them are read, new names are encoded as version 3.
+#[derive(Clone, Debug, PartialEq)]
 pub struct ExternalFileName {
     pub internal: InternalFileName,
     pub ts: i64,
+    pub version: HashVersion,
+    pub format: ImageFormat,
+    /// Not interpreted yet, kept as they are.
+    pub flags: u32,
 }
 
 /// Represents an extenal filename.
 impl ExternalFileName {
     /// Create a new `ExternalFileName` instance with the current timestamp.
-    pub fn from_uuid_and_display(uuid: &str, display: &Display) -> Self {
+    pub fn from_uuid_and_display(uuid: &str, display: &Display, secret: Option<&str>) -> Self {
+        Self::from_hash_and_display(
+            HashVersion::current(secret),
+            &uuid_hash(uuid, secret),
+            display,
+        )
+    }
+    /// Create a new `ExternalFileName` instance for an object stored under a
+    /// hash of `version` with the current timestamp.
+    pub fn from_hash_and_display(version: HashVersion, uuid_hash: &str, display: &Display) -> Self {
         ExternalFileName {
-            internal: InternalFileName::from_uuid_and_display(uuid, display),
+            internal: InternalFileName {
+                uuid_hash: uuid_hash.to_owned(),
+                display: display.to_owned(),
+            },
             ts: Utc::now().timestamp(),
+            version,
+            format: ImageFormat::Png,
+            flags: 0,
         }
     }
+    /// Whether this is a name of `uuid`'s avatar.
+    pub fn belongs_to(&self, uuid: &str, secret: Option<&str>) -> bool {
+        versioned_uuid_hash(self.version, uuid, secret).as_ref() == Some(&self.internal.uuid_hash)
+    }
     pub fn from_uri(uri: &str) -> Result<Self, Error> {
-        let encoded = match (uri.rfind('/'), uri.rfind('.')) {
-            (Some(start), Some(end)) => &uri[start + 1..end],
-            (Some(start), None) => &uri[start + 1..],
-            (None, Some(end)) => &uri[..end],
-            _ => uri,
+        // a dot before the last slash is not an extension
+        let name = uri.rsplit('/').next().unwrap_or(uri);
+        let encoded = match name.rfind('.') {
+            Some(end) => &name[..end],
+            None => name,
         };
         Self::from_encoded(encoded)
     }
     pub fn from_encoded(encoded: &str) -> Result<Self, Error> {
-        let decoded = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)?;
+        let decoded = base64::decode_config(encoded, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| NameError::InvalidName)?;
         let s = String::from_utf8(decoded).map_err(|_| NameError::InvalidUtf8)?;
-        let mut parts = s.split('#').take(3).map(String::from);
-        let uuid_hash = parts.next().ok_or(NameError::InvalidName)?;
-        let display = parts
-            .next()
-            .ok_or(NameError::InvalidName)?
-            .as_str()
-            .try_into()?;
-        let ts = parts.next().ok_or(NameError::InvalidName)?;
+        // v1 names start with the hash, later ones with their version
+        let parts = s.split('#').collect::<Vec<_>>();
+        let (version, uuid_hash, display, ts, format, flags) = match parts.as_slice() {
+            ["3", version, uuid_hash, display, ts, format, flags] => (
+                match *version {
+                    "1" => HashVersion::V1,
+                    "2" => HashVersion::V2,
+                    _ => return Err(NameError::InvalidName.into()),
+                },
+                uuid_hash,
+                display,
+                ts,
+                ImageFormat::from_extension(format)?,
+                u32::from_str_radix(flags, 16).map_err(|_| NameError::InvalidName)?,
+            ),
+            ["2", uuid_hash, display, ts] => {
+                (HashVersion::V2, uuid_hash, display, ts, ImageFormat::Png, 0)
+            }
+            [uuid_hash, display, ts, ..] => {
+                (HashVersion::V1, uuid_hash, display, ts, ImageFormat::Png, 0)
+            }
+            _ => return Err(NameError::InvalidName.into()),
+        };
         Ok(ExternalFileName {
-            internal: InternalFileName { uuid_hash, display },
-            ts: ts.parse()?,
+            internal: InternalFileName {
+                uuid_hash: uuid_hash.to_string(),
+                display: (*display).try_into().map_err(|_| NameError::InvalidName)?,
+            },
+            ts: ts.parse().map_err(|_| NameError::InvalidName)?,
+            version,
+            format,
+            flags,
         })
     }
 
     pub fn encode(&self) -> String {
+        let version = match self.version {
+            HashVersion::V1 => 1,
+            HashVersion::V2 => 2,
+        };
         base64::encode_config(
             format!(
-                "{}#{}#{}",
+                "3#{}#{}#{}#{}#{}#{:x}",
+                version,
                 &self.internal.uuid_hash,
                 &self.internal.display.as_str(),
-                self.ts
+                self.ts,
+                self.format.extension(),
+                self.flags
             ),
             base64::URL_SAFE_NO_PAD,
         )
     }
     pub fn filename(&self) -> String {
-        format!("{}.{}", self.encode(), FILE_ENDING)
+        format!("{}.{}", self.encode(), self.format.extension())
     }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use proptest::prelude::*;
 
     #[test]
     fn test_name_uuid_conversion() -> Result<(), Error> {
         let uuid = "9e697947-2990-4182-b080-533c16af4799";
         let display = &Display::Staff;
-        let name = ExternalFileName::from_uuid_and_display(uuid, display).filename();
+        let name = ExternalFileName::from_uuid_and_display(uuid, display, None).filename();
         println!("{name}");
         let external_file_name = ExternalFileName::from_uri(&name)?;
         assert_eq!(
@@ -124,4 +314,179 @@ mod test {
         assert_eq!(&external_file_name.internal.display, display);
         Ok(())
     }
+
+    #[test]
+    fn test_hash_versions() -> Result<(), Error> {
+        let uuid = "9e697947-2990-4182-b080-533c16af4799";
+        let keyed = keyed_uuid_hash(b"secret", uuid);
+        assert_eq!(keyed.len(), 64);
+        assert_ne!(keyed, sha256_uuid_hash(uuid));
+        assert_ne!(keyed, keyed_uuid_hash(b"other secret", uuid));
+
+        for version in &[HashVersion::V1, HashVersion::V2] {
+            let name = ExternalFileName {
+                internal: InternalFileName {
+                    uuid_hash: keyed.clone(),
+                    display: Display::Ndaed,
+                },
+                ts: 1337,
+                version: *version,
+                format: ImageFormat::Jpeg,
+                flags: 0,
+            };
+            let parsed = ExternalFileName::from_uri(&name.filename())?;
+            assert_eq!(parsed.version, *version);
+            assert_eq!(parsed.internal.uuid_hash, keyed);
+            assert_eq!(parsed.internal.display, Display::Ndaed);
+            assert_eq!(parsed.ts, 1337);
+            assert_eq!(parsed.format, ImageFormat::Jpeg);
+            assert!(name.filename().ends_with(".jpg"));
+        }
+
+        // names from before versioning
+        let v1 = base64::encode_config(
+            format!("{}#staff#1", sha256_uuid_hash(uuid)),
+            base64::URL_SAFE_NO_PAD,
+        );
+        let parsed = ExternalFileName::from_encoded(&v1)?;
+        assert_eq!(parsed.version, HashVersion::V1);
+        assert!(parsed.belongs_to(uuid, None));
+        assert!(parsed.belongs_to(uuid, Some("secret")));
+        assert!(!parsed.belongs_to("someone else", None));
+
+        let keyed_name =
+            ExternalFileName::from_uuid_and_display(uuid, &Display::Staff, Some("secret"));
+        assert_eq!(keyed_name.version, HashVersion::V2);
+        assert_eq!(keyed_name.internal.uuid_hash, keyed);
+        assert!(keyed_name.belongs_to(uuid, Some("secret")));
+        assert!(!keyed_name.belongs_to(uuid, Some("other secret")));
+        assert!(!keyed_name.belongs_to(uuid, None));
+        assert_eq!(
+            uuid_hashes(uuid, Some("secret")),
+            vec![
+                (HashVersion::V2, keyed.clone()),
+                (HashVersion::V1, sha256_uuid_hash(uuid))
+            ]
+        );
+        assert_eq!(
+            uuid_hashes(uuid, None),
+            vec![(HashVersion::V1, sha256_uuid_hash(uuid))]
+        );
+        assert_eq!(parsed.format, ImageFormat::Png);
+        Ok(())
+    }
+
+    fn display() -> impl Strategy<Value = Display> {
+        prop_oneof![
+            Just(Display::Private),
+            Just(Display::Staff),
+            Just(Display::Ndaed),
+            Just(Display::Vouched),
+            Just(Display::Authenticated),
+            Just(Display::Public),
+        ]
+    }
+
+    fn format() -> impl Strategy<Value = ImageFormat> {
+        prop_oneof![
+            Just(ImageFormat::Png),
+            Just(ImageFormat::Jpeg),
+            Just(ImageFormat::Webp),
+        ]
+    }
+
+    fn version() -> impl Strategy<Value = HashVersion> {
+        prop_oneof![Just(HashVersion::V1), Just(HashVersion::V2)]
+    }
+
+    proptest! {
+        #[test]
+        fn prop_round_trip(
+            uuid_hash in "[0-9a-f]{64}",
+            display in display(),
+            ts in any::<i64>(),
+            version in version(),
+            format in format(),
+            flags in any::<u32>(),
+        ) {
+            let name = ExternalFileName {
+                internal: InternalFileName { uuid_hash, display },
+                ts,
+                version,
+                format,
+                flags,
+            };
+            prop_assert_eq!(&ExternalFileName::from_uri(&name.filename()).unwrap(), &name);
+            let uri = format!("/avatar/get/id/{}", name.filename());
+            prop_assert_eq!(&ExternalFileName::from_uri(&uri).unwrap(), &name);
+        }
+
+        #[test]
+        fn prop_reads_older_versions(
+            uuid_hash in "[0-9a-f]{64}",
+            display in display(),
+            ts in any::<i64>(),
+        ) {
+            let v1 = format!("{}#{}#{}", uuid_hash, display.as_str(), ts);
+            let v2 = format!("2#{}", v1);
+            for (encoded, version) in &[(v1, HashVersion::V1), (v2, HashVersion::V2)] {
+                let uri = format!(
+                    "{}.png",
+                    base64::encode_config(encoded, base64::URL_SAFE_NO_PAD)
+                );
+                let expected = ExternalFileName {
+                    internal: InternalFileName {
+                        uuid_hash: uuid_hash.clone(),
+                        display: display.clone(),
+                    },
+                    ts,
+                    version: *version,
+                    format: ImageFormat::Png,
+                    flags: 0,
+                };
+                prop_assert_eq!(&ExternalFileName::from_uri(&uri).unwrap(), &expected);
+            }
+        }
+
+        #[test]
+        fn prop_garbage_does_not_panic(encoded in "\\PC*") {
+            let _ = ExternalFileName::from_uri(&encoded);
+        }
+    }
+
+    #[test]
+    fn test_garbage_names_are_invalid_names() {
+        let encode = |s: &[u8]| base64::encode_config(s, base64::URL_SAFE_NO_PAD);
+        let garbage = [
+            "!!garbage".to_owned(),
+            encode(&[0xff, 0xfe, 0x23]),
+            encode(b"abc#nope#1"),
+            encode(b"2#abc#staff#soon"),
+            encode(b"3#2#abc#staff#1#png#zz"),
+        ];
+        for encoded in &garbage {
+            let e = ExternalFileName::from_encoded(encoded).unwrap_err();
+            assert!(
+                e.downcast_ref::<NameError>().is_some(),
+                "{}: {}",
+                encoded,
+                e
+            );
+        }
+    }
+
+    #[test]
+    fn test_internal_name_round_trip() {
+        let internal =
+            InternalFileName::from_uuid_and_display("some-uuid", &Display::Vouched, None);
+        let parsed = InternalFileName::from_name(&internal.to_string()).unwrap();
+        assert_eq!(parsed.uuid_hash, internal.uuid_hash);
+        assert_eq!(parsed.display, Display::Vouched);
+        let history = HistoryFileName {
+            uuid_hash: internal.uuid_hash,
+            ts: 1,
+        };
+        assert!(InternalFileName::from_name(&history.to_string()).is_none());
+        assert!(InternalFileName::from_name("abc").is_none());
+    }
 }
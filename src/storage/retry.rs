@@ -0,0 +1,422 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
+use super::is_not_found;
+use super::memory::MemoryError;
+use super::s3;
+use super::Hashed;
+use super::Stat;
+use super::Storage;
+use super::Streamed;
+use super::Version;
+use crate::settings::RetrySettings;
+use actix_web::rt::time::sleep;
+use actix_web::rt::time::timeout;
+use failure::Error;
+use futures::future::BoxFuture;
+use log::warn;
+use rand::Rng;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+#[derive(Debug, Fail)]
+pub enum RetryError {
+    #[fail(display = "{} timed out", _0)]
+    Timeout(String),
+}
+
+/// Whether trying again might succeed. Missing objects and invalid requests
+/// fail right away.
+pub fn retryable(e: &Error) -> bool {
+    if let Some(e) = e.downcast_ref::<io::Error>() {
+        return matches!(
+            e.kind(),
+            io::ErrorKind::Interrupted | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+        );
+    }
+    // injected failures stand in for transient ones
+    if let Some(MemoryError::Injected(_)) = e.downcast_ref::<MemoryError>() {
+        return true;
+    }
+    e.downcast_ref::<RetryError>().is_some() || s3::is_transient(e)
+}
+
+/// A rename is not idempotent: a try which moved the object but timed out
+/// leaves nothing to move for the next one. A missing source is fine if the
+/// target is there.
+async fn moved_before(
+    storage: &impl Storage,
+    e: Error,
+    new_name: &str,
+    new_prefix: &str,
+    bucket: &str,
+) -> Result<(), Error> {
+    if is_not_found(&e) && storage.exists(new_name, new_prefix, bucket).await? {
+        return Ok(());
+    }
+    Err(e)
+}
+
+/// Backoff before retry number `retry` (counting from 1): a random duration up
+/// to `base_delay_ms * 2^(retry - 1)`, capped by `max_delay_ms`.
+fn backoff(settings: &RetrySettings, retry: u32) -> Duration {
+    let cap = settings
+        .base_delay_ms
+        .saturating_mul(1 << (retry - 1).min(32))
+        .min(settings.max_delay_ms);
+    Duration::from_millis(rand::thread_rng().gen_range(0..=cap))
+}
+
+/// Calls `call` until it succeeds, fails with an error that is not
+/// `retryable`, or `settings.attempts` are used up. Every try is limited to
+/// `settings.timeout_ms`.
+pub async fn retry<'a, T>(
+    settings: &RetrySettings,
+    what: &str,
+    call: impl Fn() -> BoxFuture<'a, Result<T, Error>>,
+) -> Result<T, Error> {
+    let mut attempt = 1;
+    loop {
+        let res = match timeout(Duration::from_millis(settings.timeout_ms), call()).await {
+            Ok(res) => res,
+            Err(_) => Err(RetryError::Timeout(what.to_owned()).into()),
+        };
+        match res {
+            Err(e) if attempt < settings.attempts && retryable(&e) => {
+                let delay = backoff(settings, attempt);
+                warn!(
+                    "{} failed (attempt {}/{}), retrying in {:?}: {}",
+                    what, attempt, settings.attempts, delay, e
+                );
+                sleep(delay).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Retries calls to `inner` according to `RetrySettings`.
+pub struct RetryingStorage<S: Storage> {
+    inner: S,
+    settings: RetrySettings,
+}
+
+impl<S: Storage> RetryingStorage<S> {
+    pub fn new(inner: S, settings: RetrySettings) -> Self {
+        RetryingStorage { inner, settings }
+    }
+}
+
+impl<S: Storage> Storage for RetryingStorage<S> {
+    fn load(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("loading {}/{}", prefix, name);
+            retry(&self.settings, &what, || {
+                self.inner.load(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn load_hashed(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Hashed, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("loading {}/{}", prefix, name);
+            retry(&self.settings, &what, || {
+                self.inner.load_hashed(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    /// Only opening the stream is retried.
+    fn load_stream(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Streamed, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("loading {}/{}", prefix, name);
+            retry(&self.settings, &what, || {
+                self.inner.load_stream(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn load_version(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        version_id: Option<&str>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        let version_id = version_id.map(String::from);
+        Box::pin(async move {
+            let what = format!("loading {}/{}", prefix, name);
+            retry(&self.settings, &what, || {
+                self.inner
+                    .load_version(&name, &prefix, &bucket, version_id.as_deref())
+            })
+            .await
+        })
+    }
+    fn save(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        buf: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("saving {}/{}", prefix, name);
+            retry(&self.settings, &what, || {
+                self.inner.save(&name, &prefix, &bucket, buf.clone())
+            })
+            .await
+        })
+    }
+    fn delete(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("deleting {}/{}", prefix, name);
+            retry(&self.settings, &what, || {
+                self.inner.delete(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn delete_many(
+        &self,
+        names: &[String],
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (names, prefix, bucket) = (names.to_vec(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("deleting {} files from {}", names.len(), prefix);
+            retry(&self.settings, &what, || {
+                self.inner.delete_many(&names, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>> {
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            retry(&self.settings, "saving tmp file", || {
+                self.inner.save_tmp(&bucket, buf.clone())
+            })
+            .await
+        })
+    }
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        let (new_name, new_prefix) = (new_name.to_owned(), new_prefix.to_owned());
+        Box::pin(async move {
+            let what = format!("copying {}/{}", prefix, name);
+            retry(&self.settings, &what, || {
+                self.inner
+                    .copy(&name, &prefix, &new_name, &new_prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn rename(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        let (new_name, new_prefix) = (new_name.to_owned(), new_prefix.to_owned());
+        Box::pin(async move {
+            let what = format!("renaming {}/{}", prefix, name);
+            let tried = AtomicBool::new(false);
+            retry(&self.settings, &what, || {
+                let retried = tried.swap(true, Ordering::SeqCst);
+                let rename = self
+                    .inner
+                    .rename(&name, &prefix, &new_name, &new_prefix, &bucket);
+                let (new_name, new_prefix, bucket) = (&new_name, &new_prefix, &bucket);
+                Box::pin(async move {
+                    match rename.await {
+                        Err(e) if retried => {
+                            moved_before(&self.inner, e, new_name, new_prefix, bucket).await
+                        }
+                        res => res,
+                    }
+                })
+            })
+            .await
+        })
+    }
+    fn exists(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<bool, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("checking {}/{}", prefix, name);
+            retry(&self.settings, &what, || {
+                self.inner.exists(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn stat(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Stat, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("checking {}/{}", prefix, name);
+            retry(&self.settings, &what, || {
+                self.inner.stat(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+    fn list(&self, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Vec<String>, Error>> {
+        let (prefix, bucket) = (prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("listing {}", prefix);
+            retry(&self.settings, &what, || self.inner.list(&prefix, &bucket)).await
+        })
+    }
+    fn versions(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<Version>, Error>> {
+        let (name, prefix, bucket) = (name.to_owned(), prefix.to_owned(), bucket.to_owned());
+        Box::pin(async move {
+            let what = format!("listing versions of {}/{}", prefix, name);
+            retry(&self.settings, &what, || {
+                self.inner.versions(&name, &prefix, &bucket)
+            })
+            .await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::memory::MemoryStorage;
+    use std::sync::atomic::AtomicUsize;
+
+    fn settings() -> RetrySettings {
+        RetrySettings {
+            attempts: 3,
+            base_delay_ms: 1,
+            max_delay_ms: 5,
+            timeout_ms: 50,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transient_failure_is_retried() -> Result<(), Error> {
+        let storage = RetryingStorage::new(MemoryStorage::default().fail_nth_save(1), settings());
+        Storage::save(&storage, "a", "264", "b", b"a".to_vec()).await?;
+        assert_eq!(storage.inner.saves(), 2);
+        assert_eq!(Storage::load(&storage, "a", "264", "b").await?, b"a");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_all_attempts() -> Result<(), Error> {
+        let storage = RetryingStorage::new(MemoryStorage::default().fail_prefix("264"), settings());
+        assert!(Storage::save(&storage, "a", "264", "b", b"a".to_vec())
+            .await
+            .is_err());
+        assert_eq!(storage.inner.saves(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_permanent_failure_is_not_retried() {
+        let calls = AtomicUsize::new(0);
+        let res: Result<(), _> = retry(&settings(), "loading", || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Box::pin(async { Err(MemoryError::NotFound.into()) })
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_hanging_call_times_out_and_is_retried() -> Result<(), Error> {
+        let calls = AtomicUsize::new(0);
+        let res = retry(&settings(), "loading", || {
+            if calls.fetch_add(1, Ordering::SeqCst) == 0 {
+                Box::pin(futures::future::pending())
+            } else {
+                Box::pin(async { Ok(1) })
+            }
+        })
+        .await?;
+        assert_eq!(res, 1);
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rename_moved_by_an_earlier_try() -> Result<(), Error> {
+        let storage = MemoryStorage::default();
+        storage.insert("a", "trash/264", "b", b"a".to_vec());
+        assert!(moved_before(
+            &storage,
+            MemoryError::NotFound.into(),
+            "a",
+            "trash/264",
+            "b"
+        )
+        .await
+        .is_ok());
+        assert!(moved_before(
+            &storage,
+            MemoryError::NotFound.into(),
+            "a",
+            "history/264",
+            "b"
+        )
+        .await
+        .is_err());
+        let injected = MemoryError::Injected(String::new()).into();
+        assert!(moved_before(&storage, injected, "a", "trash/264", "b")
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_backoff_is_capped() {
+        let settings = settings();
+        for retry in 1..40 {
+            assert!(backoff(&settings, retry) <= Duration::from_millis(5));
+        }
+    }
+}
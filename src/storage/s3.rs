@@ -0,0 +1,1016 @@
+// DEBT: Quoting the lint:
+//     non-local `impl` definition, `impl` blocks should be written at the same
+//     level as their item
+#![allow(non_local_definitions)]
+
+use super::s3_api::S3Api;
+use super::sha256_hex;
+use super::Hashed;
+use super::Stat;
+use super::Storage;
+use super::Streamed;
+use super::Version;
+use super::TMP;
+use crate::settings::AvatarSettings;
+use crate::settings::ServerSideEncryption;
+use bytes::BytesMut;
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Utc;
+use failure::Error;
+use futures::future::BoxFuture;
+use futures::stream::TryStreamExt;
+use log::debug;
+use log::info;
+use rusoto_core::credential::StaticProvider;
+use rusoto_core::Client;
+use rusoto_core::HttpClient;
+use rusoto_core::Region;
+use rusoto_core::RusotoError;
+use rusoto_s3::CopyObjectError;
+use rusoto_s3::CopyObjectRequest;
+use rusoto_s3::Delete;
+use rusoto_s3::DeleteObjectError;
+use rusoto_s3::DeleteObjectRequest;
+use rusoto_s3::DeleteObjectsError;
+use rusoto_s3::DeleteObjectsRequest;
+use rusoto_s3::GetObjectError;
+use rusoto_s3::GetObjectRequest;
+use rusoto_s3::HeadObjectError;
+use rusoto_s3::HeadObjectOutput;
+use rusoto_s3::HeadObjectRequest;
+use rusoto_s3::ListObjectVersionsError;
+use rusoto_s3::ListObjectVersionsRequest;
+use rusoto_s3::ListObjectsV2Error;
+use rusoto_s3::ListObjectsV2Request;
+use rusoto_s3::ObjectIdentifier;
+use rusoto_s3::PutObjectError;
+use rusoto_s3::PutObjectOutput;
+use rusoto_s3::PutObjectRequest;
+use rusoto_s3::S3Client;
+use sha2::Digest;
+use std::collections::HashMap;
+use std::ops::Add;
+use uuid::Uuid;
+
+#[derive(Debug, Fail)]
+pub enum S3Error {
+    #[fail(display = "empty body received")]
+    NoBody,
+    #[fail(display = "no such object")]
+    NotFound,
+    #[fail(display = "virtual-hosted style addressing is not supported")]
+    VirtualHostedStyle,
+    #[fail(display = "access key id and secret access key must be set together")]
+    IncompleteCredentials,
+    #[fail(display = "checksum mismatch for {}", _0)]
+    ChecksumMismatch(String),
+    #[fail(display = "failed to delete [{}]", _0)]
+    DeleteFailed(String),
+}
+
+/// Whether a failed request might succeed when sent again: connection
+/// errors, throttling and server errors.
+fn transient<E>(e: &RusotoError<E>) -> bool {
+    match e {
+        RusotoError::HttpDispatch(_) => true,
+        RusotoError::Unknown(res) => res.status.is_server_error() || res.status.as_u16() == 429,
+        _ => false,
+    }
+}
+
+macro_rules! transient {
+    ($e:ident, $($error:ty),*) => {
+        $(
+            if let Some(e) = $e.downcast_ref::<RusotoError<$error>>() {
+                return transient(e);
+            }
+        )*
+    };
+}
+
+/// `transient` for any error returned by `S3Storage`.
+pub fn is_transient(e: &Error) -> bool {
+    transient!(
+        e,
+        GetObjectError,
+        PutObjectError,
+        DeleteObjectError,
+        DeleteObjectsError,
+        CopyObjectError,
+        HeadObjectError,
+        ListObjectsV2Error,
+        ListObjectVersionsError
+    );
+    false
+}
+
+/// User metadata key of the hex encoded SHA-256 of an object.
+const SHA256: &str = "sha256";
+
+/// Most keys S3 accepts in a single DeleteObjects request.
+const MAX_DELETE_KEYS: usize = 1000;
+
+/// Whether `e` was raised by S3 or the S3 client.
+pub fn is_s3_error(e: &Error) -> bool {
+    macro_rules! any {
+        ($($error:ty),*) => {
+            false $(|| e.downcast_ref::<RusotoError<$error>>().is_some())*
+        };
+    }
+    e.downcast_ref::<S3Error>().is_some()
+        || any!(
+            GetObjectError,
+            PutObjectError,
+            DeleteObjectError,
+            DeleteObjectsError,
+            CopyObjectError,
+            HeadObjectError,
+            ListObjectsV2Error,
+            ListObjectVersionsError
+        )
+}
+
+/// Whether `e` is a bare 404 of a request failing with `E`. That's how
+/// missing keys show up for operations without a `NoSuchKey` error, like the
+/// source of a copy, and for HEAD responses, which carry no body.
+fn is_404<E: std::error::Error + Send + Sync + 'static>(e: &Error) -> bool {
+    matches!(
+        e.downcast_ref::<RusotoError<E>>(),
+        Some(RusotoError::Unknown(res)) if res.status.as_u16() == 404
+    )
+}
+
+/// Whether `e` reports a missing object.
+pub fn is_not_found(e: &Error) -> bool {
+    if let Some(S3Error::NotFound) = e.downcast_ref::<S3Error>() {
+        return true;
+    }
+    if let Some(RusotoError::Service(GetObjectError::NoSuchKey(_))) =
+        e.downcast_ref::<RusotoError<GetObjectError>>()
+    {
+        return true;
+    }
+    if let Some(RusotoError::Service(HeadObjectError::NoSuchKey(_))) =
+        e.downcast_ref::<RusotoError<HeadObjectError>>()
+    {
+        return true;
+    }
+    is_404::<GetObjectError>(e) || is_404::<CopyObjectError>(e) || is_404::<HeadObjectError>(e)
+}
+
+fn content_type(key: &str) -> &'static str {
+    match key.rsplit('.').next() {
+        Some("png") => "image/png",
+        Some("json") => "application/json",
+        _ => "application/octet-stream",
+    }
+}
+
+impl ServerSideEncryption {
+    /// The `x-amz-server-side-encryption` and `x-amz-server-side-encryption-aws-kms-key-id`
+    /// values.
+    fn headers(&self) -> (Option<String>, Option<String>) {
+        match self {
+            ServerSideEncryption::None => (None, None),
+            ServerSideEncryption::S3 => (Some(String::from("AES256")), None),
+            ServerSideEncryption::Kms { key_id } => (Some(String::from("aws:kms")), key_id.clone()),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct S3Storage<C: S3Api = S3Client> {
+    pub s3_client: C,
+    /// Sets the `expires` header on tmp files, which the bucket lifecycle rule acts on.
+    pub tmp_ttl: Duration,
+    pub cache_control: String,
+    pub encryption: ServerSideEncryption,
+}
+
+impl S3Storage {
+    /// Uses AWS unless a custom endpoint is configured.
+    pub fn from_settings(settings: &AvatarSettings) -> Result<Self, Error> {
+        let tmp_ttl = Duration::minutes(settings.tmp_ttl_minutes);
+        let cache_control = settings.s3_cache_control.clone();
+        let encryption = settings.s3_encryption.clone();
+        let endpoint = match &settings.s3_endpoint {
+            Some(endpoint) => endpoint,
+            None => {
+                return Ok(S3Storage {
+                    s3_client: S3Client::new(Region::default()),
+                    tmp_ttl,
+                    cache_control,
+                    encryption,
+                })
+            }
+        };
+        if !endpoint.path_style {
+            return Err(S3Error::VirtualHostedStyle.into());
+        }
+        let region = Region::Custom {
+            name: endpoint.region.clone(),
+            endpoint: endpoint.url.clone(),
+        };
+        let client = match (&endpoint.access_key_id, &endpoint.secret_access_key) {
+            (Some(key), Some(secret)) => Client::new_with(
+                StaticProvider::new_minimal(key.clone(), secret.clone()),
+                HttpClient::new()?,
+            ),
+            (None, None) => Client::shared(),
+            _ => return Err(S3Error::IncompleteCredentials.into()),
+        };
+        info!("using S3 endpoint {}", endpoint.url);
+        Ok(S3Storage {
+            s3_client: S3Client::new_with_client(client, region),
+            tmp_ttl,
+            cache_control,
+            encryption,
+        })
+    }
+}
+
+impl<C: S3Api> S3Storage<C> {
+    /// Builds a put carrying content type, cache control, encryption and checksums.
+    /// Returns the hex encoded MD5 to verify the upload with.
+    fn put_request(&self, key: String, bucket: &str, buf: Vec<u8>) -> (PutObjectRequest, String) {
+        let md5 = md5::Md5::digest(&buf);
+        let mut metadata = HashMap::new();
+        metadata.insert(SHA256.to_owned(), sha256_hex(&buf));
+        let (server_side_encryption, ssekms_key_id) = self.encryption.headers();
+        let put = PutObjectRequest {
+            bucket: bucket.to_owned(),
+            content_type: Some(content_type(&key).to_owned()),
+            cache_control: Some(self.cache_control.clone()),
+            content_md5: Some(base64::encode(md5)),
+            content_length: Some(buf.len() as i64),
+            metadata: Some(metadata),
+            server_side_encryption,
+            ssekms_key_id,
+            key,
+            body: Some(buf.into()),
+            ..Default::default()
+        };
+        (put, format!("{:x}", md5))
+    }
+
+    /// S3 rejects bodies not matching `Content-MD5` itself. For single part
+    /// uploads the ETag is the MD5 of the stored object, which catches anything
+    /// mangling the request before it got signed. Except for SSE-KMS, where the
+    /// ETag is not an MD5 of the data.
+    async fn put(&self, put: PutObjectRequest, md5: &str) -> Result<PutObjectOutput, Error> {
+        let key = put.key.clone();
+        let kms = matches!(self.encryption, ServerSideEncryption::Kms { .. });
+        let res = self.s3_client.put_object(put).await?;
+        match res.e_tag.as_deref().map(|etag| etag.trim_matches('"')) {
+            Some(etag) if !kms && etag != md5 => Err(S3Error::ChecksumMismatch(key).into()),
+            _ => Ok(res),
+        }
+    }
+
+    /// `HEAD`s an object, `None` if it does not exist.
+    async fn head(&self, head: HeadObjectRequest) -> Result<Option<HeadObjectOutput>, Error> {
+        match self.s3_client.head_object(head).await {
+            Ok(res) => Ok(Some(res)),
+            // HEAD responses carry no body, so a missing key mostly shows up as a bare 404
+            Err(RusotoError::Service(HeadObjectError::NoSuchKey(_))) => Ok(None),
+            Err(RusotoError::Unknown(ref res)) if res.status.as_u16() == 404 => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl<C: S3Api> Storage for S3Storage<C> {
+    fn load(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        self.load_version(name, prefix, bucket, None)
+    }
+    fn load_version(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        version_id: Option<&str>,
+    ) -> BoxFuture<'_, Result<Vec<u8>, Error>> {
+        let download = GetObjectRequest {
+            bucket: bucket.to_owned(),
+            key: format!("{}/{}", prefix, name),
+            version_id: version_id.map(String::from),
+            ..Default::default()
+        };
+        let name = name.to_owned();
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            let res = self.s3_client.get_object(download).await?;
+            info!(
+                "downloaded {} from {} with version_id: {}",
+                name,
+                bucket,
+                res.version_id.as_deref().unwrap_or("-"),
+            );
+            let stream = res.body.ok_or(S3Error::NoBody)?;
+            let body = stream
+                .map_ok(|b| BytesMut::from(&b[..]))
+                .try_concat()
+                .await?;
+            Ok(body.to_vec())
+        })
+    }
+    fn load_hashed(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Hashed, Error>> {
+        let download = GetObjectRequest {
+            bucket: bucket.to_owned(),
+            key: format!("{}/{}", prefix, name),
+            ..Default::default()
+        };
+        Box::pin(async move {
+            let res = self.s3_client.get_object(download).await?;
+            let sha256 = res
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get(SHA256).cloned());
+            let stream = res.body.ok_or(S3Error::NoBody)?;
+            let body = stream
+                .map_ok(|b| BytesMut::from(&b[..]))
+                .try_concat()
+                .await?;
+            Ok(match sha256 {
+                Some(sha256) => Hashed {
+                    buf: body.to_vec(),
+                    sha256,
+                },
+                // stored before hashes were recorded
+                None => Hashed::new(body.to_vec()),
+            })
+        })
+    }
+
+    fn load_stream(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Streamed, Error>> {
+        let download = GetObjectRequest {
+            bucket: bucket.to_owned(),
+            key: format!("{}/{}", prefix, name),
+            ..Default::default()
+        };
+        Box::pin(async move {
+            let res = self.s3_client.get_object(download).await?;
+            let sha256 = res
+                .metadata
+                .as_ref()
+                .and_then(|metadata| metadata.get(SHA256).cloned());
+            let stream = res.body.ok_or(S3Error::NoBody)?;
+            Ok(Streamed {
+                stream: Box::pin(stream.map_err(Error::from)),
+                sha256,
+                size: res.content_length.map(|l| l as u64),
+            })
+        })
+    }
+    fn save(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        buf: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let (put, md5) = self.put_request(format!("{}/{}", prefix, name), bucket, buf);
+        let name = name.to_owned();
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            let res = self.put(put, &md5).await?;
+            info!(
+                "uploaded {} to {} with version_id: {}",
+                name,
+                bucket,
+                res.version_id.as_deref().unwrap_or("-"),
+            );
+            Ok(())
+        })
+    }
+    fn delete(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<(), Error>> {
+        let delete = DeleteObjectRequest {
+            bucket: bucket.to_owned(),
+            key: format!("{}/{}", prefix, name),
+            ..Default::default()
+        };
+        let name = name.to_owned();
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            let res = self.s3_client.delete_object(delete).await?;
+            info!(
+                "deleted {} from {} with version_id: {}",
+                name,
+                bucket,
+                res.version_id.as_deref().unwrap_or("-"),
+            );
+            Ok(())
+        })
+    }
+    fn delete_many(
+        &self,
+        names: &[String],
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let delete_reqs = names
+            .chunks(MAX_DELETE_KEYS)
+            .map(|chunk| DeleteObjectsRequest {
+                bucket: bucket.to_owned(),
+                delete: Delete {
+                    objects: chunk
+                        .iter()
+                        .map(|name| ObjectIdentifier {
+                            key: format!("{}/{}", prefix, name),
+                            ..Default::default()
+                        })
+                        .collect(),
+                    quiet: None,
+                },
+                ..Default::default()
+            })
+            .collect::<Vec<_>>();
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            let mut failed = vec![];
+            for delete_req in delete_reqs {
+                let res = self.s3_client.delete_objects(delete_req).await?;
+                let deleted = res.deleted.unwrap_or_default();
+                let errors = res
+                    .errors
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|e| e.key.unwrap_or_default())
+                    .collect::<Vec<_>>();
+                debug!(
+                    "deleted [{}] from {}. ([{}])",
+                    deleted
+                        .into_iter()
+                        .map(|d| d.key.unwrap_or_default())
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    bucket,
+                    errors.join(", "),
+                );
+                failed.extend(errors);
+            }
+            if !failed.is_empty() {
+                return Err(S3Error::DeleteFailed(failed.join(", ")).into());
+            }
+            Ok(())
+        })
+    }
+    fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>> {
+        let name = Uuid::new_v4().to_simple().to_string();
+        let (put, md5) = self.put_request(format!("{}/{}", TMP, &name), bucket, buf);
+        let put = PutObjectRequest {
+            expires: Some(Utc::now().add(self.tmp_ttl).to_rfc3339()),
+            ..put
+        };
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            let res = self.put(put, &md5).await?;
+            info!(
+                "created tmp file {} in {} with version_id: {}",
+                name,
+                bucket,
+                res.version_id.as_deref().unwrap_or("-"),
+            );
+            Ok(name)
+        })
+    }
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        // Keys only consist of url safe characters, no need to encode the source.
+        // Copies are not encrypted like their source unless asked to.
+        let (server_side_encryption, ssekms_key_id) = self.encryption.headers();
+        let copy = CopyObjectRequest {
+            bucket: bucket.to_owned(),
+            key: format!("{}/{}", new_prefix, new_name),
+            copy_source: format!("{}/{}/{}", bucket, prefix, name),
+            server_side_encryption,
+            ssekms_key_id,
+            ..Default::default()
+        };
+        let name = name.to_owned();
+        let new_name = new_name.to_owned();
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            let res = self.s3_client.copy_object(copy).await?;
+            info!(
+                "copied {} to {} in {} with version_id: {}",
+                name,
+                new_name,
+                bucket,
+                res.version_id.as_deref().unwrap_or("-"),
+            );
+            Ok(())
+        })
+    }
+    fn exists(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<bool, Error>> {
+        let head = HeadObjectRequest {
+            bucket: bucket.to_owned(),
+            key: format!("{}/{}", prefix, name),
+            ..Default::default()
+        };
+        Box::pin(async move { Ok(self.head(head).await?.is_some()) })
+    }
+    fn stat(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Stat, Error>> {
+        let head = HeadObjectRequest {
+            bucket: bucket.to_owned(),
+            key: format!("{}/{}", prefix, name),
+            ..Default::default()
+        };
+        Box::pin(async move {
+            let res = self.head(head).await?.ok_or(S3Error::NotFound)?;
+            Ok(Stat {
+                size: res.content_length.unwrap_or_default() as u64,
+                modified: res
+                    .last_modified
+                    .and_then(|m| DateTime::parse_from_rfc2822(&m).ok())
+                    .map(|m| m.with_timezone(&Utc)),
+                etag: res.e_tag,
+            })
+        })
+    }
+    fn list(&self, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<Vec<String>, Error>> {
+        let key_prefix = format!("{}/", prefix);
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            let mut names = Vec::new();
+            let mut continuation_token = None;
+            loop {
+                let list = ListObjectsV2Request {
+                    bucket: bucket.clone(),
+                    prefix: Some(key_prefix.clone()),
+                    continuation_token,
+                    ..Default::default()
+                };
+                let res = self.s3_client.list_objects_v2(list).await?;
+                names.extend(
+                    res.contents
+                        .unwrap_or_default()
+                        .into_iter()
+                        .filter_map(|o| o.key)
+                        .filter_map(|k| k.strip_prefix(&key_prefix).map(String::from)),
+                );
+                match res.next_continuation_token {
+                    Some(token) if res.is_truncated.unwrap_or_default() => {
+                        continuation_token = Some(token)
+                    }
+                    _ => break,
+                }
+            }
+            Ok(names)
+        })
+    }
+    fn versions(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<Vec<Version>, Error>> {
+        let key = format!("{}/{}", prefix, name);
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            let mut versions = Vec::new();
+            let mut key_marker = None;
+            let mut version_id_marker = None;
+            loop {
+                let list = ListObjectVersionsRequest {
+                    bucket: bucket.clone(),
+                    prefix: Some(key.clone()),
+                    key_marker,
+                    version_id_marker,
+                    ..Default::default()
+                };
+                let res = self.s3_client.list_object_versions(list).await?;
+                versions.extend(
+                    res.versions
+                        .unwrap_or_default()
+                        .into_iter()
+                        // the prefix also matches longer keys
+                        .filter(|v| v.key.as_deref() == Some(key.as_str()))
+                        .filter_map(|v| {
+                            Some(Version {
+                                version_id: v.version_id?,
+                                modified: v
+                                    .last_modified
+                                    .and_then(|m| DateTime::parse_from_rfc3339(&m).ok())
+                                    .map(|m| m.timestamp()),
+                                size: v.size.unwrap_or_default() as u64,
+                                latest: v.is_latest.unwrap_or_default(),
+                            })
+                        }),
+                );
+                if !res.is_truncated.unwrap_or_default() {
+                    break;
+                }
+                key_marker = res.next_key_marker;
+                version_id_marker = res.next_version_id_marker;
+            }
+            Ok(versions)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::settings::RetrySettings;
+    use crate::storage::retry::RetryingStorage;
+    use actix_web::http::StatusCode;
+    use rusoto_core::request::BufferedHttpResponse;
+    use rusoto_core::request::HttpDispatchError;
+    use rusoto_s3::CopyObjectOutput;
+    use rusoto_s3::DeleteObjectOutput;
+    use rusoto_s3::DeleteObjectsOutput;
+    use rusoto_s3::DeletedObject;
+    use rusoto_s3::GetObjectOutput;
+    use rusoto_s3::ListObjectVersionsOutput;
+    use rusoto_s3::ListObjectsV2Output;
+    use rusoto_s3::ObjectVersion;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+    use std::sync::Mutex;
+
+    /// `(key, server_side_encryption, ssekms_key_id)` of a write.
+    type Recorded = (String, Option<String>, Option<String>);
+
+    /// Records puts and copies, serves `versions` and answers gets with the
+    /// requested version id.
+    #[derive(Default)]
+    struct MockS3 {
+        requests: Mutex<Vec<Recorded>>,
+        versions: Vec<ObjectVersion>,
+        /// Loses the response of the first delete, so the copy of a retried
+        /// rename finds its source gone.
+        lose_first_delete: bool,
+        deletes: AtomicUsize,
+        /// Keys DeleteObjects reports as failed.
+        undeletable: Vec<String>,
+        /// Number of keys of each DeleteObjects request.
+        batches: Mutex<Vec<usize>>,
+    }
+
+    fn not_found() -> BufferedHttpResponse {
+        BufferedHttpResponse {
+            status: StatusCode::NOT_FOUND,
+            body: Default::default(),
+            headers: Default::default(),
+        }
+    }
+
+    impl S3Api for MockS3 {
+        fn get_object(
+            &self,
+            input: GetObjectRequest,
+        ) -> BoxFuture<'_, Result<GetObjectOutput, RusotoError<GetObjectError>>> {
+            let body = input.version_id.unwrap_or_else(|| String::from("latest"));
+            Box::pin(async move {
+                Ok(GetObjectOutput {
+                    body: Some(body.into_bytes().into()),
+                    ..Default::default()
+                })
+            })
+        }
+        fn put_object(
+            &self,
+            input: PutObjectRequest,
+        ) -> BoxFuture<'_, Result<PutObjectOutput, RusotoError<PutObjectError>>> {
+            self.requests.lock().unwrap().push((
+                input.key,
+                input.server_side_encryption,
+                input.ssekms_key_id,
+            ));
+            let e_tag = input
+                .content_md5
+                .and_then(|md5| base64::decode(md5).ok())
+                .map(|md5| {
+                    let hex: String = md5.iter().map(|b| format!("{:02x}", b)).collect();
+                    format!("\"{}\"", hex)
+                });
+            Box::pin(async move {
+                Ok(PutObjectOutput {
+                    e_tag,
+                    ..Default::default()
+                })
+            })
+        }
+        fn delete_object(
+            &self,
+            _: DeleteObjectRequest,
+        ) -> BoxFuture<'_, Result<DeleteObjectOutput, RusotoError<DeleteObjectError>>> {
+            let ret = match self.deletes.fetch_add(1, Ordering::SeqCst) {
+                0 if self.lose_first_delete => Err(RusotoError::HttpDispatch(
+                    HttpDispatchError::new(String::from("connection reset")),
+                )),
+                _ => Ok(DeleteObjectOutput::default()),
+            };
+            Box::pin(async move { ret })
+        }
+        fn delete_objects(
+            &self,
+            input: DeleteObjectsRequest,
+        ) -> BoxFuture<'_, Result<DeleteObjectsOutput, RusotoError<DeleteObjectsError>>> {
+            let objects = input.delete.objects;
+            self.batches.lock().unwrap().push(objects.len());
+            let (errors, deleted): (Vec<_>, Vec<_>) = objects
+                .into_iter()
+                .partition(|o| self.undeletable.contains(&o.key));
+            let output = DeleteObjectsOutput {
+                deleted: Some(
+                    deleted
+                        .into_iter()
+                        .map(|o| DeletedObject {
+                            key: Some(o.key),
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+                errors: Some(
+                    errors
+                        .into_iter()
+                        .map(|o| rusoto_s3::Error {
+                            key: Some(o.key),
+                            code: Some(String::from("AccessDenied")),
+                            ..Default::default()
+                        })
+                        .collect(),
+                ),
+            };
+            Box::pin(async move { Ok(output) })
+        }
+        fn copy_object(
+            &self,
+            input: CopyObjectRequest,
+        ) -> BoxFuture<'_, Result<CopyObjectOutput, RusotoError<CopyObjectError>>> {
+            let ret = match self.deletes.load(Ordering::SeqCst) {
+                0 => Ok(CopyObjectOutput::default()),
+                _ => Err(RusotoError::Unknown(not_found())),
+            };
+            self.requests.lock().unwrap().push((
+                input.key,
+                input.server_side_encryption,
+                input.ssekms_key_id,
+            ));
+            Box::pin(async move { ret })
+        }
+        fn head_object(
+            &self,
+            _: HeadObjectRequest,
+        ) -> BoxFuture<'_, Result<HeadObjectOutput, RusotoError<HeadObjectError>>> {
+            Box::pin(async { Ok(HeadObjectOutput::default()) })
+        }
+        fn list_objects_v2(
+            &self,
+            _: ListObjectsV2Request,
+        ) -> BoxFuture<'_, Result<ListObjectsV2Output, RusotoError<ListObjectsV2Error>>> {
+            unimplemented!()
+        }
+        fn list_object_versions(
+            &self,
+            _: ListObjectVersionsRequest,
+        ) -> BoxFuture<'_, Result<ListObjectVersionsOutput, RusotoError<ListObjectVersionsError>>>
+        {
+            let versions = self.versions.clone();
+            Box::pin(async move {
+                Ok(ListObjectVersionsOutput {
+                    versions: Some(versions),
+                    ..Default::default()
+                })
+            })
+        }
+    }
+
+    fn mock_storage(encryption: ServerSideEncryption) -> S3Storage<MockS3> {
+        S3Storage {
+            s3_client: MockS3::default(),
+            tmp_ttl: Duration::hours(1),
+            cache_control: String::from("max-age=60"),
+            encryption,
+        }
+    }
+
+    async fn encryption_of_writes(
+        encryption: ServerSideEncryption,
+    ) -> Result<Vec<Recorded>, Error> {
+        let storage = mock_storage(encryption);
+        Storage::save(&storage, "a.png", "264", "bucket", b"hello".to_vec()).await?;
+        let tmp = Storage::save_tmp(&storage, "bucket", b"hello".to_vec()).await?;
+        Storage::copy(&storage, "a.png", "264", "b.png", "264", "bucket").await?;
+        let requests = storage.s3_client.requests.into_inner().unwrap();
+        assert_eq!(
+            requests.iter().map(|r| r.0.as_str()).collect::<Vec<_>>(),
+            vec!["264/a.png", &format!("tmp/{}", tmp), "264/b.png"]
+        );
+        Ok(requests)
+    }
+
+    #[tokio::test]
+    async fn test_load_version() -> Result<(), Error> {
+        let storage = mock_storage(ServerSideEncryption::None);
+        assert_eq!(
+            Storage::load_version(&storage, "a.png", "40", "bucket", Some("v1")).await?,
+            b"v1"
+        );
+        assert_eq!(
+            Storage::load(&storage, "a.png", "40", "bucket").await?,
+            b"latest"
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_versions_of_exact_key() -> Result<(), Error> {
+        let version = |key: &str, id: &str, latest: bool| ObjectVersion {
+            key: Some(key.to_owned()),
+            version_id: Some(id.to_owned()),
+            is_latest: Some(latest),
+            last_modified: Some(String::from("2020-01-02T03:04:05.000Z")),
+            size: Some(3),
+            ..Default::default()
+        };
+        let mut storage = mock_storage(ServerSideEncryption::None);
+        storage.s3_client.versions = vec![
+            version("40/a.png", "v2", true),
+            version("40/a.png.old", "x", true),
+            version("40/a.png", "v1", false),
+        ];
+        let versions = Storage::versions(&storage, "a.png", "40", "bucket").await?;
+        assert_eq!(
+            versions,
+            vec![
+                Version {
+                    version_id: String::from("v2"),
+                    modified: Some(1577934245),
+                    size: 3,
+                    latest: true,
+                },
+                Version {
+                    version_id: String::from("v1"),
+                    modified: Some(1577934245),
+                    size: 3,
+                    latest: false,
+                },
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_no_encryption_by_default() -> Result<(), Error> {
+        for (_, sse, key_id) in encryption_of_writes(ServerSideEncryption::None).await? {
+            assert_eq!(sse, None);
+            assert_eq!(key_id, None);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sse_s3_on_all_writes() -> Result<(), Error> {
+        for (_, sse, key_id) in encryption_of_writes(ServerSideEncryption::S3).await? {
+            assert_eq!(sse.as_deref(), Some("AES256"));
+            assert_eq!(key_id, None);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sse_kms_with_key_id_on_all_writes() -> Result<(), Error> {
+        let encryption = ServerSideEncryption::Kms {
+            key_id: Some(String::from("alias/fossil")),
+        };
+        for (_, sse, key_id) in encryption_of_writes(encryption).await? {
+            assert_eq!(sse.as_deref(), Some("aws:kms"));
+            assert_eq!(key_id.as_deref(), Some("alias/fossil"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_transient_errors() {
+        let unknown = |status: u16| {
+            let e: Error = RusotoError::<GetObjectError>::Unknown(BufferedHttpResponse {
+                status: StatusCode::from_u16(status).unwrap(),
+                body: Default::default(),
+                headers: Default::default(),
+            })
+            .into();
+            e
+        };
+        assert!(is_transient(&unknown(503)));
+        assert!(is_transient(&unknown(429)));
+        assert!(!is_transient(&unknown(403)));
+        assert!(is_transient(
+            &RusotoError::<PutObjectError>::HttpDispatch(HttpDispatchError::new(String::from(
+                "connection reset"
+            )))
+            .into()
+        ));
+        assert!(!is_transient(
+            &RusotoError::Service(HeadObjectError::NoSuchKey(String::new())).into()
+        ));
+        assert!(!is_transient(&S3Error::NoBody.into()));
+    }
+
+    #[tokio::test]
+    async fn test_retried_rename_of_a_moved_object() -> Result<(), Error> {
+        let mut storage = mock_storage(ServerSideEncryption::None);
+        storage.s3_client.lose_first_delete = true;
+        let storage = RetryingStorage::new(
+            storage,
+            RetrySettings {
+                attempts: 3,
+                base_delay_ms: 1,
+                max_delay_ms: 5,
+                timeout_ms: 50,
+            },
+        );
+        Storage::rename(&storage, "a.png", "264", "a.png", "trash/264", "bucket").await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delete_many() -> Result<(), Error> {
+        let mut storage = mock_storage(ServerSideEncryption::None);
+        storage.s3_client.undeletable = vec![String::from("264/1500.png")];
+        let names = (0..2500).map(|i| format!("{}.png", i)).collect::<Vec<_>>();
+        let e = Storage::delete_many(&storage, &names, "264", "bucket")
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            e.downcast_ref::<S3Error>(),
+            Some(S3Error::DeleteFailed(keys)) if keys == "264/1500.png"
+        ));
+        assert_eq!(
+            *storage.s3_client.batches.lock().unwrap(),
+            [1000, 1000, 500]
+        );
+
+        storage.s3_client.undeletable.clear();
+        Storage::delete_many(&storage, &names[..10], "264", "bucket").await?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_not_found() {
+        assert!(is_not_found(
+            &RusotoError::Service(GetObjectError::NoSuchKey(String::new())).into()
+        ));
+        assert!(is_not_found(
+            &RusotoError::<CopyObjectError>::Unknown(not_found()).into()
+        ));
+        assert!(is_not_found(
+            &RusotoError::<HeadObjectError>::Unknown(not_found()).into()
+        ));
+        assert!(is_not_found(&S3Error::NotFound.into()));
+        assert!(!is_not_found(&S3Error::NoBody.into()));
+        assert!(is_s3_error(&S3Error::NoBody.into()));
+        assert!(!is_s3_error(&failure::format_err!("other")));
+        assert!(!is_not_found(
+            &RusotoError::<GetObjectError>::HttpDispatch(HttpDispatchError::new(String::new()))
+                .into()
+        ));
+    }
+
+    #[test]
+    fn test_put_request_carries_metadata() {
+        let storage = S3Storage {
+            s3_client: S3Client::new(Region::default()),
+            tmp_ttl: Duration::hours(1),
+            cache_control: String::from("max-age=60"),
+            encryption: ServerSideEncryption::None,
+        };
+        let (put, md5) =
+            storage.put_request(String::from("264/a.png"), "bucket", b"hello".to_vec());
+        assert_eq!(md5, "5d41402abc4b2a76b9719d911017c592");
+        assert_eq!(put.content_md5.as_deref(), Some("XUFAKrxLKna5cZ2REBfFkg=="));
+        assert_eq!(put.content_type.as_deref(), Some("image/png"));
+        assert_eq!(put.cache_control.as_deref(), Some("max-age=60"));
+        assert_eq!(put.content_length, Some(5));
+        assert_eq!(
+            put.metadata.unwrap().get(SHA256).map(String::as_str),
+            Some("2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824")
+        );
+        assert_eq!(content_type("history/abc.json"), "application/json");
+        assert_eq!(content_type("tmp/abc"), "application/octet-stream");
+    }
+}
@@ -0,0 +1,106 @@
+use futures::future::BoxFuture;
+use rusoto_core::RusotoError;
+use rusoto_s3::CopyObjectError;
+use rusoto_s3::CopyObjectOutput;
+use rusoto_s3::CopyObjectRequest;
+use rusoto_s3::DeleteObjectError;
+use rusoto_s3::DeleteObjectOutput;
+use rusoto_s3::DeleteObjectRequest;
+use rusoto_s3::DeleteObjectsError;
+use rusoto_s3::DeleteObjectsOutput;
+use rusoto_s3::DeleteObjectsRequest;
+use rusoto_s3::GetObjectError;
+use rusoto_s3::GetObjectOutput;
+use rusoto_s3::GetObjectRequest;
+use rusoto_s3::HeadObjectError;
+use rusoto_s3::HeadObjectOutput;
+use rusoto_s3::HeadObjectRequest;
+use rusoto_s3::ListObjectVersionsError;
+use rusoto_s3::ListObjectVersionsOutput;
+use rusoto_s3::ListObjectVersionsRequest;
+use rusoto_s3::ListObjectsV2Error;
+use rusoto_s3::ListObjectsV2Output;
+use rusoto_s3::ListObjectsV2Request;
+use rusoto_s3::PutObjectError;
+use rusoto_s3::PutObjectOutput;
+use rusoto_s3::PutObjectRequest;
+use rusoto_s3::S3Client;
+use rusoto_s3::S3;
+
+type S3Future<'a, T, E> = BoxFuture<'a, Result<T, RusotoError<E>>>;
+
+/// The part of the `S3` API the storage backend uses. Small enough to be
+/// replaced by a mock in tests.
+pub trait S3Api: Send + Sync {
+    fn get_object(&self, input: GetObjectRequest) -> S3Future<'_, GetObjectOutput, GetObjectError>;
+    fn put_object(&self, input: PutObjectRequest) -> S3Future<'_, PutObjectOutput, PutObjectError>;
+    fn delete_object(
+        &self,
+        input: DeleteObjectRequest,
+    ) -> S3Future<'_, DeleteObjectOutput, DeleteObjectError>;
+    fn delete_objects(
+        &self,
+        input: DeleteObjectsRequest,
+    ) -> S3Future<'_, DeleteObjectsOutput, DeleteObjectsError>;
+    fn copy_object(
+        &self,
+        input: CopyObjectRequest,
+    ) -> S3Future<'_, CopyObjectOutput, CopyObjectError>;
+    fn head_object(
+        &self,
+        input: HeadObjectRequest,
+    ) -> S3Future<'_, HeadObjectOutput, HeadObjectError>;
+    fn list_objects_v2(
+        &self,
+        input: ListObjectsV2Request,
+    ) -> S3Future<'_, ListObjectsV2Output, ListObjectsV2Error>;
+    fn list_object_versions(
+        &self,
+        input: ListObjectVersionsRequest,
+    ) -> S3Future<'_, ListObjectVersionsOutput, ListObjectVersionsError>;
+}
+
+impl S3Api for S3Client {
+    fn get_object(&self, input: GetObjectRequest) -> S3Future<'_, GetObjectOutput, GetObjectError> {
+        S3::get_object(self, input)
+    }
+    fn put_object(&self, input: PutObjectRequest) -> S3Future<'_, PutObjectOutput, PutObjectError> {
+        S3::put_object(self, input)
+    }
+    fn delete_object(
+        &self,
+        input: DeleteObjectRequest,
+    ) -> S3Future<'_, DeleteObjectOutput, DeleteObjectError> {
+        S3::delete_object(self, input)
+    }
+    fn delete_objects(
+        &self,
+        input: DeleteObjectsRequest,
+    ) -> S3Future<'_, DeleteObjectsOutput, DeleteObjectsError> {
+        S3::delete_objects(self, input)
+    }
+    fn copy_object(
+        &self,
+        input: CopyObjectRequest,
+    ) -> S3Future<'_, CopyObjectOutput, CopyObjectError> {
+        S3::copy_object(self, input)
+    }
+    fn head_object(
+        &self,
+        input: HeadObjectRequest,
+    ) -> S3Future<'_, HeadObjectOutput, HeadObjectError> {
+        S3::head_object(self, input)
+    }
+    fn list_objects_v2(
+        &self,
+        input: ListObjectsV2Request,
+    ) -> S3Future<'_, ListObjectsV2Output, ListObjectsV2Error> {
+        S3::list_objects_v2(self, input)
+    }
+    fn list_object_versions(
+        &self,
+        input: ListObjectVersionsRequest,
+    ) -> S3Future<'_, ListObjectVersionsOutput, ListObjectVersionsError> {
+        S3::list_object_versions(self, input)
+    }
+}
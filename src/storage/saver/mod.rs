@@ -1,9 +1,4 @@
-#[cfg(feature = "local-fs")]
-pub mod filesystem;
-
-#[cfg(not(feature = "local-fs"))]
-pub mod s3;
-
+use crate::storage::Storage;
 use failure::Error;
 use futures::future::BoxFuture;
 
@@ -23,4 +18,80 @@ pub trait Saver: Sync + Send + Sized {
         bucket: &str,
     ) -> BoxFuture<'_, Result<(), Error>>;
     fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>>;
+    /// Copies an object within `bucket` without passing it through the service.
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>>;
+    /// Moves an object within `bucket`. Backends without a native rename copy
+    /// and delete.
+    fn rename(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        let name = name.to_owned();
+        let prefix = prefix.to_owned();
+        let new_name = new_name.to_owned();
+        let new_prefix = new_prefix.to_owned();
+        let bucket = bucket.to_owned();
+        Box::pin(async move {
+            self.copy(&name, &prefix, &new_name, &new_prefix, &bucket)
+                .await?;
+            self.delete(&name, &prefix, &bucket).await
+        })
+    }
+}
+
+impl<T: Storage> Saver for T {
+    fn save(
+        &self,
+        name: &str,
+        prefix: &str,
+        bucket: &str,
+        buf: Vec<u8>,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        Storage::save(self, name, prefix, bucket, buf)
+    }
+    fn delete(&self, name: &str, prefix: &str, bucket: &str) -> BoxFuture<'_, Result<(), Error>> {
+        Storage::delete(self, name, prefix, bucket)
+    }
+    fn delete_many(
+        &self,
+        names: &[String],
+        prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        Storage::delete_many(self, names, prefix, bucket)
+    }
+    fn save_tmp(&self, bucket: &str, buf: Vec<u8>) -> BoxFuture<'_, Result<String, Error>> {
+        Storage::save_tmp(self, bucket, buf)
+    }
+    fn copy(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        Storage::copy(self, name, prefix, new_name, new_prefix, bucket)
+    }
+    fn rename(
+        &self,
+        name: &str,
+        prefix: &str,
+        new_name: &str,
+        new_prefix: &str,
+        bucket: &str,
+    ) -> BoxFuture<'_, Result<(), Error>> {
+        Storage::rename(self, name, prefix, new_name, new_prefix, bucket)
+    }
 }
@@ -2,9 +2,7 @@ use crate::retrieve::app::retrieve_app;
 use crate::send::app::internal_send_app;
 use crate::send::app::send_app;
 use crate::settings::AvatarSettings;
-use crate::storage::loader::filesystem::FilesystemLoader;
-use crate::storage::saver::filesystem::FilesystemSaver;
-use actix_web::body::MessageBody;
+use crate::storage::memory::MemoryStorage;
 use actix_web::dev::Service;
 use actix_web::middleware::Logger;
 use actix_web::test;
@@ -28,7 +26,6 @@ use serde::Deserialize;
 use serde_json::Value;
 use std::env;
 use std::marker::Send;
-use std::sync::Arc;
 use std::sync::Mutex;
 
 #[derive(Clone)]
@@ -72,11 +69,11 @@ async fn healthz_check_returns_success() -> Result<(), Error> {
     let app = App::new()
         .wrap(Logger::default())
         .service(web::scope("").service(crate::healthz::healthz_app()));
-    let mut app = test::init_service(app).await;
+    let app = test::init_service(app).await;
 
     let req = test::TestRequest::get().uri("/healthz").to_request();
 
-    let res = test::call_service(&mut app, req).await;
+    let res = test::call_service(&app, req).await;
     assert!(res.response().status().is_success());
 
     Ok(())
@@ -89,19 +86,11 @@ async fn avatar_test() -> Result<(), Error> {
         "actix_http=trace,actix_web=trace,dino_park_fossil=trace",
     );
 
-    let path = env::temp_dir();
-
-    let saver = Data::new(FilesystemSaver {
-        path: Arc::new(path.clone()),
-    });
-    let loader = Data::new(FilesystemLoader {
-        path: Arc::new(path.clone()),
-    });
+    let storage = Data::new(MemoryStorage::default());
 
     let avatar_settings = Data::new(AvatarSettings {
         s3_bucket: String::from("test_avatar_bucket"),
-        retrieve_by_id_path: String::from("/avatar/get/id/"),
-        picture_api_url: String::from("http://localhost"),
+        ..AvatarSettings::for_testing()
     });
 
     let cis_client = Data::new(MockCisClient {});
@@ -135,17 +124,16 @@ async fn avatar_test() -> Result<(), Error> {
             srv.call(req)
         })
         .app_data(cis_client)
-        .app_data(loader)
-        .app_data(saver)
+        .app_data(storage)
         .app_data(avatar_settings)
         .app_data(cache)
         .service(
             web::scope("/avatar")
-                .service(retrieve_app::<MockCisClient, FilesystemLoader>())
-                .service(send_app::<FilesystemSaver, FilesystemLoader>()),
+                .service(retrieve_app::<MockCisClient, MemoryStorage>())
+                .service(send_app::<MemoryStorage, MemoryStorage>()),
         )
-        .service(internal_send_app::<FilesystemSaver, FilesystemLoader>());
-    let mut app = test::init_service(app).await;
+        .service(internal_send_app::<MemoryStorage>());
+    let app = test::init_service(app).await;
 
     let sample_image_data = include_bytes!("data/sample_image.png");
 
@@ -178,7 +166,7 @@ async fn avatar_test() -> Result<(), Error> {
         pub uuid: String,
     }
 
-    let res_json: UuidResponse = test::call_and_read_body_json(&mut app, req).await;
+    let res_json: UuidResponse = test::call_and_read_body_json(&app, req).await;
 
     // make sure returned uuid is valid
     assert!(res_json.uuid.parse::<uuid::Uuid>().is_ok());
@@ -190,7 +178,7 @@ async fn avatar_test() -> Result<(), Error> {
             uuid = res_json.uuid
         ))
         .insert_header(("Content-type", "application/json"))
-        .set_json(&serde_json::json!({
+        .set_json(serde_json::json!({
             "intermediate": res_json.uuid,
             "display": "public"
             // don't supply the old_url field as we're saving a new avatar
@@ -203,7 +191,7 @@ async fn avatar_test() -> Result<(), Error> {
         pub url: String,
     }
 
-    let res_json: PictureResponse = test::call_and_read_body_json(&mut app, req).await;
+    let res_json: PictureResponse = test::call_and_read_body_json(&app, req).await;
     let mut iccp_crc = None;
 
     for size in &["raw", "528", "264", "100", "40"] {
@@ -211,14 +199,11 @@ async fn avatar_test() -> Result<(), Error> {
             .uri(&format!("{}?size={}", res_json.url, size))
             .to_request();
 
-        let res = test::call_service(&mut app, req).await;
+        let res = test::call_service(&app, req).await;
 
         assert!(res.status().is_success());
 
-        let bytes: Bytes = res
-            .into_body()
-            .try_into_bytes()
-            .expect("could not read back bytes");
+        let bytes: Bytes = test::read_body(res).await;
 
         // test that iCCP still exists in downscaled image (and it is valid)
         let mut decoder = lodepng::Decoder::new();
@@ -241,5 +226,34 @@ async fn avatar_test() -> Result<(), Error> {
 
     assert_ne!(iccp_crc, None, "no crc checksums were compared?!");
 
+    // pictures carry an ETag and are not sent again if it still matches
+    let req = test::TestRequest::get().uri(&res_json.url).to_request();
+    let res = test::call_service(&app, req).await;
+    let etag = res
+        .headers()
+        .get("ETag")
+        .expect("no ETag returned")
+        .to_str()?
+        .to_owned();
+    let req = test::TestRequest::get()
+        .uri(&res_json.url)
+        .insert_header(("If-None-Match", etag))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), actix_web::http::StatusCode::NOT_MODIFIED);
+
+    // errors come with a stable code
+    let req = test::TestRequest::post()
+        .uri("/internal/save/gone?@@testScope@@=authenticated")
+        .set_json(serde_json::json!({
+            "intermediate": "gone",
+            "display": "public"
+        }))
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), actix_web::http::StatusCode::NOT_FOUND);
+    let body: serde_json::Value = test::read_body_json(res).await;
+    assert_eq!(body["code"], "missing_intermediate");
+
     Ok(())
 }